@@ -0,0 +1,60 @@
+// Fixed size bitset over subject indices
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bitset {
+  word_list: Vec<u64>,
+  len: usize,
+}
+
+impl Bitset {
+  pub fn new(len: usize) -> Self {
+    Bitset {
+      word_list: vec![0; (len + 63) / 64],
+      len,
+    }
+  }
+
+  pub fn full(len: usize) -> Self {
+    let mut x = Bitset {
+      word_list: vec![!0; (len + 63) / 64],
+      len,
+    };
+    if len % 64 != 0 {
+      *x.word_list.last_mut().unwrap() = (1 << (len % 64)) - 1;
+    }
+    x
+  }
+
+  pub fn insert(&mut self, i: usize) {
+    assert!(i < self.len);
+    self.word_list[i / 64] |= 1 << (i % 64);
+  }
+
+  pub fn and_with(&mut self, other: &Bitset) {
+    assert_eq!(self.len, other.len);
+    self.word_list.iter_mut().zip(other.word_list.iter()).for_each(|(a, b)| *a &= *b);
+  }
+
+  pub fn and_not_with(&mut self, other: &Bitset) {
+    assert_eq!(self.len, other.len);
+    self.word_list.iter_mut().zip(other.word_list.iter()).for_each(|(a, b)| *a &= !*b);
+  }
+
+  pub fn or_with(&mut self, other: &Bitset) {
+    assert_eq!(self.len, other.len);
+    self.word_list.iter_mut().zip(other.word_list.iter()).for_each(|(a, b)| *a |= *b);
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+    self.word_list.iter().enumerate().flat_map(|(i_word, word)| {
+      let mut word = *word;
+      std::iter::from_fn(move || {
+        if word == 0 {
+          return None;
+        }
+        let i_bit = word.trailing_zeros() as usize;
+        word &= word - 1;
+        Some(i_word * 64 + i_bit)
+      })
+    })
+  }
+}
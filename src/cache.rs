@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct CacheStat {
+  pub hit_count: u64,
+  pub miss_count: u64,
+  pub entry_count: usize,
+  pub weight: usize,
+  pub capacity: usize,
+}
+
+struct CacheEntry<V> {
+  value: V,
+  weight: usize,
+  insert_time: Instant,
+  last_use: u64,
+}
+
+struct LruCacheInner<K, V> {
+  entry_map: HashMap<K, CacheEntry<V>>,
+  use_order: BTreeMap<u64, K>, // `last_use` of every entry, least recent first
+  weight: usize,
+  tick: u64,
+}
+
+impl<K, V> LruCacheInner<K, V>
+where
+  K: Hash + Eq + Clone,
+{
+  fn remove(&mut self, key: &K) {
+    if let Some(entry) = self.entry_map.remove(key) {
+      self.use_order.remove(&entry.last_use);
+      self.weight -= entry.weight;
+    }
+  }
+}
+
+// LRU cache bounded by the total weight of its entries, expired entries are dropped when looked up or evicted
+pub struct LruCache<K, V> {
+  capacity: usize,
+  ttl: Duration,
+  inner: Mutex<LruCacheInner<K, V>>,
+  hit_count: AtomicU64,
+  miss_count: AtomicU64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+  K: Hash + Eq + Clone,
+  V: Clone,
+{
+  pub fn new(capacity: usize, ttl: Duration) -> Self {
+    LruCache {
+      capacity,
+      ttl,
+      inner: Mutex::new(LruCacheInner { entry_map: HashMap::new(), use_order: BTreeMap::new(), weight: 0, tick: 0 }),
+      hit_count: AtomicU64::new(0),
+      miss_count: AtomicU64::new(0),
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  // An entry failing `is_usable` counts as a miss and is left for `insert` to replace
+  pub fn get<F>(&self, key: &K, is_usable: F) -> Option<V>
+  where
+    F: FnOnce(&V) -> bool,
+  {
+    let mut inner = self.inner.lock().unwrap();
+    let inner = &mut *inner;
+    inner.tick += 1;
+    let tick = inner.tick;
+    let value = match inner.entry_map.get_mut(key) {
+      Some(entry) if entry.insert_time.elapsed() >= self.ttl => {
+        inner.remove(key);
+        None
+      },
+      Some(entry) if is_usable(&entry.value) => {
+        inner.use_order.remove(&entry.last_use);
+        inner.use_order.insert(tick, key.clone());
+        entry.last_use = tick;
+        Some(entry.value.clone())
+      },
+      _ => None,
+    };
+    match value {
+      Some(_) => self.hit_count.fetch_add(1, Ordering::Relaxed),
+      None => self.miss_count.fetch_add(1, Ordering::Relaxed),
+    };
+    value
+  }
+
+  // Values heavier than the whole capacity are not cached
+  pub fn insert(&self, key: K, value: V, weight: usize) {
+    if weight > self.capacity {
+      return;
+    }
+    let mut inner = self.inner.lock().unwrap();
+    let inner = &mut *inner;
+    inner.remove(&key);
+    while inner.weight + weight > self.capacity {
+      let oldest = inner.use_order.values().next().cloned().unwrap();
+      inner.remove(&oldest);
+    }
+    inner.tick += 1;
+    let last_use = inner.tick;
+    inner.weight += weight;
+    inner.use_order.insert(last_use, key.clone());
+    inner.entry_map.insert(key, CacheEntry { value, weight, insert_time: Instant::now(), last_use });
+  }
+
+  pub fn stat(&self) -> CacheStat {
+    let inner = self.inner.lock().unwrap();
+    CacheStat {
+      hit_count: self.hit_count.load(Ordering::Relaxed),
+      miss_count: self.miss_count.load(Ordering::Relaxed),
+      entry_count: inner.entry_map.len(),
+      weight: inner.weight,
+      capacity: self.capacity,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cache(capacity: usize, ttl: Duration) -> LruCache<u32, u32> {
+    LruCache::new(capacity, ttl)
+  }
+
+  #[test]
+  fn evict_least_recently_used() {
+    let c = cache(5, Duration::from_secs(600));
+    c.insert(1, 10, 2);
+    c.insert(2, 20, 2);
+    assert_eq!(c.get(&1, |_| true), Some(10));
+    c.insert(3, 30, 2);
+    assert_eq!(c.get(&2, |_| true), None);
+    assert_eq!(c.get(&1, |_| true), Some(10));
+    assert_eq!(c.get(&3, |_| true), Some(30));
+    c.insert(4, 40, 5);
+    assert_eq!((c.get(&1, |_| true), c.get(&3, |_| true), c.get(&4, |_| true)), (None, None, Some(40)));
+    let stat = c.stat();
+    assert_eq!((stat.entry_count, stat.weight), (1, 5));
+  }
+
+  #[test]
+  fn expired_entry_is_a_miss_and_frees_its_weight() {
+    let c = cache(5, Duration::from_millis(20));
+    c.insert(1, 10, 3);
+    assert_eq!(c.get(&1, |_| true), Some(10));
+    std::thread::sleep(Duration::from_millis(40));
+    assert_eq!(c.get(&1, |_| true), None);
+    let stat = c.stat();
+    assert_eq!((stat.entry_count, stat.weight, stat.hit_count, stat.miss_count), (0, 0, 1, 1));
+  }
+
+  #[test]
+  fn reinsert_replaces_value_and_weight() {
+    let c = cache(5, Duration::from_secs(600));
+    c.insert(1, 10, 3);
+    c.insert(2, 20, 1);
+    c.insert(1, 11, 4);
+    assert_eq!((c.get(&1, |_| true), c.get(&2, |_| true)), (Some(11), Some(20)));
+    assert_eq!(c.stat().weight, 5);
+    assert_eq!(c.get(&1, |x| *x > 11), None);
+    assert_eq!(c.get(&1, |_| true), Some(11));
+  }
+
+  #[test]
+  fn reject_value_heavier_than_capacity() {
+    let c = cache(5, Duration::from_secs(600));
+    c.insert(1, 10, 2);
+    c.insert(2, 20, 6);
+    assert_eq!((c.get(&1, |_| true), c.get(&2, |_| true)), (Some(10), None));
+    let stat = c.stat();
+    assert_eq!((stat.entry_count, stat.weight), (1, 2));
+    let c = cache(0, Duration::from_secs(600));
+    c.insert(1, 10, 1);
+    assert_eq!(c.stat().entry_count, 0);
+  }
+}
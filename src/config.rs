@@ -0,0 +1,260 @@
+extern crate toml;
+
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+const DEFAULT_CONFIG_PATH: &str = "hako.toml";
+
+// Size variants served by the bgm.tv image host, first segment of `image_partial_url`
+pub const COVER_SIZE_LIST: [&str; 5] = ["l", "c", "m", "s", "g"];
+
+const USAGE: &str = "Usage: hako [--config <path>] [--<key> <value>]...
+       hako [--config <path>] --list-covers <size>[,<size>]...
+
+Settings are read from the TOML file (default `hako.toml` when present),
+then from `HAKO_<KEY>` environment variables, then from the command line.
+
+Keys:
+  bind           address to listen on (127.0.0.1)
+  port           port to listen on (8080)
+  db-path        packed database, `<db-path>_mmap` must sit next to it (packed.db)
+  static-dir     directory served under /static (./static)
+  template-dir   directory holding the Tera templates (template)
+  workers        HTTP worker threads, 0 for one per core (0)
+  page-size      subjects per page (25)
+  tag-panel-size most frequent tags shown in the search panel (10)
+  query-cache-capacity
+                 search results and facet counts kept in the query cache, 0 to disable (65536)
+  query-cache-ttl
+                 seconds a cached search stays valid (600)
+  subject-url    subject link template, `{id}` (https://bgm.tv/subject/{id})
+  cover-url      cover image template, `{size}`, `{path}` or the whole `{partial}`
+                 image_partial_url (https://lain.bgm.tv/pic/cover/{size}/{path})
+  cover-size     one of l, c, m, s, g, empty for the size stored by the packer ()
+  cover-cache-dir
+                 local covers laid out as `<size>/<path>`, served under /img,
+                 empty to disable ()
+
+`--list-covers` prints `<size>/<path>` of every cover for an offline fetch
+into cover-cache-dir, then exits.";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub bind: String,
+  pub port: u16,
+  pub db_path: String,
+  pub static_dir: String,
+  pub template_dir: String,
+  pub workers: usize,
+  pub page_size: usize,
+  pub tag_panel_size: usize,
+  pub query_cache_capacity: usize,
+  pub query_cache_ttl: u64,
+  pub subject_url: String,
+  pub cover_url: String,
+  pub cover_size: String,
+  pub cover_cache_dir: String,
+  #[serde(skip)]
+  pub list_covers: Option<Vec<String>>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      bind: "127.0.0.1".to_string(),
+      port: 8080,
+      db_path: "packed.db".to_string(),
+      static_dir: "./static".to_string(),
+      template_dir: "template".to_string(),
+      workers: 0,
+      page_size: 25,
+      tag_panel_size: 10,
+      query_cache_capacity: 65536,
+      query_cache_ttl: 600,
+      subject_url: "https://bgm.tv/subject/{id}".to_string(),
+      cover_url: "https://lain.bgm.tv/pic/cover/{size}/{path}".to_string(),
+      cover_size: String::new(),
+      cover_cache_dir: String::new(),
+      list_covers: None,
+    }
+  }
+}
+
+impl Config {
+  // Exits the process with a message on `--help` or any invalid setting
+  pub fn load() -> Self {
+    let arg_list = std::env::args().skip(1).collect::<Vec<_>>();
+    if arg_list.iter().any(|x| x == "--help" || x == "-h") {
+      println!("{}", USAGE);
+      std::process::exit(0);
+    }
+    match Config::load_from(&arg_list) {
+      Ok(config) => config,
+      Err(error_list) => {
+        for error in error_list {
+          eprintln!("Config error: {}", error);
+        }
+        eprintln!("Run with --help for the list of settings");
+        std::process::exit(2);
+      }
+    }
+  }
+
+  fn load_from(arg_list: &[String]) -> Result<Self, Vec<String>> {
+    let mut cli_list = Vec::new();
+    let mut config_path = None;
+    let mut list_covers = None;
+    let mut iter = arg_list.iter();
+    while let Some(arg) = iter.next() {
+      let key = match arg.strip_prefix("--") {
+        Some(key) => key,
+        None => return Err(vec![format!("unexpected argument `{}`", arg)]),
+      };
+      let value = match iter.next() {
+        Some(value) => value,
+        None => return Err(vec![format!("missing value for `--{}`", key)]),
+      };
+      if key == "config" {
+        config_path = Some(value.clone());
+      }
+      else if key == "list-covers" {
+        list_covers = Some(value.split(',').map(|x| x.to_string()).collect::<Vec<_>>());
+      }
+      else {
+        cli_list.push((key.replace('-', "_"), value.clone()));
+      }
+    }
+    let config_path = config_path.or_else(|| std::env::var("HAKO_CONFIG").ok());
+
+    let mut config = match &config_path {
+      Some(path) => Config::read_file(path)?,
+      None if Path::new(DEFAULT_CONFIG_PATH).is_file() => Config::read_file(DEFAULT_CONFIG_PATH)?,
+      None => Config::default(),
+    };
+    config.list_covers = list_covers;
+
+    let mut error_list = Vec::new();
+    let env_list = std::env::vars().filter_map(|(k, v)| match k.strip_prefix("HAKO_") {
+      Some("CONFIG") | None => None,
+      Some(key) => Some((key.to_lowercase(), v)),
+    }).collect::<Vec<_>>();
+    for (key, value) in env_list.iter().chain(cli_list.iter()) {
+      if let Err(e) = config.set(key, value) {
+        error_list.push(e);
+      }
+    }
+    error_list.extend(config.validate());
+    if error_list.is_empty() { Ok(config) } else { Err(error_list) }
+  }
+
+  fn read_file(path: &str) -> Result<Self, Vec<String>> {
+    let s = std::fs::read_to_string(path).map_err(|e| vec![format!("cannot read `{}`: {}", path, e)])?;
+    toml::from_str(&s).map_err(|e| vec![format!("bad config file `{}`: {}", path, e)])
+  }
+
+  fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+      value.parse().map_err(|_| format!("bad value `{}` for `{}`", value, key))
+    }
+    match key {
+      "bind" => self.bind = value.to_string(),
+      "port" => self.port = parse(key, value)?,
+      "db_path" => self.db_path = value.to_string(),
+      "static_dir" => self.static_dir = value.to_string(),
+      "template_dir" => self.template_dir = value.to_string(),
+      "workers" => self.workers = parse(key, value)?,
+      "page_size" => self.page_size = parse(key, value)?,
+      "tag_panel_size" => self.tag_panel_size = parse(key, value)?,
+      "query_cache_capacity" => self.query_cache_capacity = parse(key, value)?,
+      "query_cache_ttl" => self.query_cache_ttl = parse(key, value)?,
+      "subject_url" => self.subject_url = value.to_string(),
+      "cover_url" => self.cover_url = value.to_string(),
+      "cover_size" => self.cover_size = value.to_string(),
+      "cover_cache_dir" => self.cover_cache_dir = value.to_string(),
+      _ => return Err(format!("unknown setting `{}`", key)),
+    }
+    Ok(())
+  }
+
+  fn validate(&self) -> Vec<String> {
+    let mut error_list = Vec::new();
+    if self.port == 0 {
+      error_list.push("port must not be 0".to_string());
+    }
+    if (self.bind.as_str(), self.port).to_socket_addrs().map(|mut x| x.next().is_none()).unwrap_or(true) {
+      error_list.push(format!("cannot resolve bind address `{}`", self.bind));
+    }
+    for path in [self.db_path.clone(), format!("{}_mmap", self.db_path)].iter() {
+      if !Path::new(path).is_file() {
+        error_list.push(format!("database file `{}` not found", path));
+      }
+    }
+    for (key, path) in [("static_dir", &self.static_dir), ("template_dir", &self.template_dir)].iter() {
+      if !Path::new(path).is_dir() {
+        error_list.push(format!("{} `{}` is not a directory", key, path));
+      }
+    }
+    if !(1..=100).contains(&self.page_size) {
+      error_list.push(format!("page_size must be within 1..=100, got {}", self.page_size));
+    }
+    if !(1..=100).contains(&self.tag_panel_size) {
+      error_list.push(format!("tag_panel_size must be within 1..=100, got {}", self.tag_panel_size));
+    }
+    for (key, url, placeholder_list) in [("subject_url", &self.subject_url, &["id"][..]), ("cover_url", &self.cover_url, &["size", "path", "partial"][..])].iter() {
+      if !(url.starts_with("http://") || url.starts_with("https://") || url.starts_with('/')) {
+        error_list.push(format!("{} `{}` must be an http(s) URL or an absolute path", key, url));
+      }
+      for name in url.split('{').skip(1).map(|x| x.split('}').next().unwrap()) {
+        if !placeholder_list.contains(&name) {
+          error_list.push(format!("{} `{}` has unknown placeholder `{{{}}}`, expected one of {:?}", key, url, name, placeholder_list));
+        }
+      }
+    }
+    if !self.subject_url.contains("{id}") {
+      error_list.push(format!("subject_url `{}` must contain `{{id}}`", self.subject_url));
+    }
+    if !(self.cover_url.contains("{path}") || self.cover_url.contains("{partial}")) {
+      error_list.push(format!("cover_url `{}` must contain `{{path}}` or `{{partial}}`", self.cover_url));
+    }
+    if !(self.cover_size.is_empty() || COVER_SIZE_LIST.contains(&self.cover_size.as_str())) {
+      error_list.push(format!("cover_size must be empty or one of {:?}, got `{}`", COVER_SIZE_LIST, self.cover_size));
+    }
+    if !self.cover_cache_dir.is_empty() && !Path::new(&self.cover_cache_dir).is_dir() {
+      error_list.push(format!("cover_cache_dir `{}` is not a directory", self.cover_cache_dir));
+    }
+    if let Some(size_list) = &self.list_covers {
+      for size in size_list.iter().filter(|x| !COVER_SIZE_LIST.contains(&x.as_str())) {
+        error_list.push(format!("--list-covers size must be one of {:?}, got `{}`", COVER_SIZE_LIST, size));
+      }
+    }
+    error_list
+  }
+
+  pub fn bind_addr(&self) -> String {
+    if self.bind.contains(':') { format!("[{}]:{}", self.bind, self.port) } else { format!("{}:{}", self.bind, self.port) }
+  }
+
+  pub fn subject_url(&self, subject_id: u32) -> String {
+    self.subject_url.replace("{id}", &subject_id.to_string())
+  }
+
+  // Empty `cover_size` keeps the size already in `partial`
+  pub fn cover_url(&self, partial: &str) -> String {
+    let (partial_size, path) = split_cover_partial(partial);
+    let size = if self.cover_size.is_empty() { partial_size } else { self.cover_size.as_str() };
+    self.cover_url.replace("{partial}", partial).replace("{size}", size).replace("{path}", path)
+  }
+
+  pub fn template_glob(&self) -> String {
+    format!("{}/**/*", self.template_dir.trim_end_matches('/'))
+  }
+}
+
+// `l/12/34/5678_abcd.jpg` into (`l`, `12/34/5678_abcd.jpg`), a partial without a size segment counts as `l`
+pub fn split_cover_partial(partial: &str) -> (&str, &str) {
+  match partial.split_once('/') {
+    Some((size, path)) if COVER_SIZE_LIST.contains(&size) => (size, path),
+    _ => ("l", partial),
+  }
+}
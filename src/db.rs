@@ -1,10 +1,35 @@
 extern crate bincode;
 extern crate memmap;
+extern crate rayon;
 
+use rayon::prelude::*;
 use std::path::Path;
 use std::io::BufReader;
+use std::collections::{BTreeMap, HashMap};
+use crate::bitset::Bitset;
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicU64};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+static DB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+const SEARCH_CHUNK_SIZE: usize = 512;
+
+// Relevance model
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+const NAME_CN_BOOST: f32 = 1.5;
+const NAME_BOOST: f32 = 1.0;
+const FUZZY_MIN_SIMILARITY: f32 = 0.6;
+const FUZZY_WEIGHT: f32 = 0.5;
+const POPULARITY_WEIGHT: f32 = 1.0;
+
+// User similarity, candidates by favourite overlap are re-ranked with relation correlation
+const SIMILAR_USER_CANDIDATE_SIZE: usize = 200;
+
+// Username suggestion, the fuzzy pass scans at most this many names
+const USERNAME_FUZZY_SCAN_SIZE: usize = 10000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum PackedSubjectSubtype {
   Unknown,
   TV,
@@ -13,6 +38,41 @@ pub enum PackedSubjectSubtype {
   Movie,
 }
 
+// Broadcast season by month of first air date
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Season {
+  Winter,
+  Spring,
+  Summer,
+  Autumn,
+}
+
+impl Season {
+  pub fn from_month(m: u8) -> Option<Season> {
+    match m {
+      1..=3 => Some(Season::Winter),
+      4..=6 => Some(Season::Spring),
+      7..=9 => Some(Season::Summer),
+      10..=12 => Some(Season::Autumn),
+      _ => None,
+    }
+  }
+
+  pub fn first_month(&self) -> u8 {
+    *self as u8 * 3 + 1
+  }
+}
+
+// Bangumi collection type of a subject for a user, in bangumi's order
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CollectionState {
+  Wish,
+  Watched,
+  Watching,
+  OnHold,
+  Dropped,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PackedSubject {
   pub subject_id: u32,
@@ -38,6 +98,8 @@ struct PackedDatabasePersistenceTable {
   user_id_list: Vec<u32>,
   user_username_list: Vec<String>,
   user_fav_list: Vec<Vec<u32>>, // Hako r1
+  user_collection_list: Vec<Vec<(u32, CollectionState)>>, // Hako r2
+  excluded_user_list: Vec<(u32, String)>, // Hako r3, UID and username of users left out of the model
   tag_name_list: Vec<String>,
 }
 
@@ -45,9 +107,27 @@ pub struct DB<'a> {
   persistence_table: PackedDatabasePersistenceTable,
   map_table_handle: memmap::Mmap,
   map_table: &'a [u16],
+  static_sort_order_table: Vec<Vec<u32>>,
+  tag_bitset_list: Vec<Bitset>,
+  tag_count_list: Vec<u32>,
+  tag_frequency_order: Vec<u32>,
+  year_bitset_map: BTreeMap<u16, Bitset>,
+  season_bitset_list: Vec<Bitset>,
+  sub_type_bitset_list: Vec<Bitset>,
+  r18_bitset: Bitset,
+  title_prefix_index: Vec<(String, u32)>,
+  username_index: HashMap<String, u32>, // case-folded username to index into `user_id_list`
+  username_prefix_index: Vec<(String, u32)>, // sorted case-folded usernames with their index into `user_id_list`
+  username_length_order: Vec<(usize, u32)>, // char count and index into `username_prefix_index`, shortest first
+  excluded_user_list: Vec<(u32, String)>, // users left out of `map_table` for too few favourites, by id
+  excluded_username_index: HashMap<String, u32>, // case-folded username to index into `excluded_user_list`
+  subject_fav_user_index: Vec<Vec<u32>>, // index into `subject_packed_list` to indexes into `user_id_list` which favourited it
+  avg_title_len: (f32, f32), // (name_cn, name) in chars, over non-empty titles
+  max_rating_count: u32,
+  generation: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum SearchRange {
   Range(u32, u32),
   RangeTo(u32),
@@ -55,7 +135,7 @@ pub enum SearchRange {
   RangeFull,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum SortMode {
   Recommend,
   Relative,
@@ -65,30 +145,176 @@ pub enum SortMode {
   FavCount,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum Relation<T: Clone> {
   Include(T),
   Exclude(T),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum SearchMode<T: Clone> {
   ExactMatch(T),
   PartialMatch(T),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// A tag only counts when its weight reaches `min_weight` and it is among the `top_n` heaviest tags of the subject
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TagFilter {
+  pub tag_id: u32,
+  pub min_weight: Option<f32>,
+  pub top_n: Option<u8>,
+}
+
+impl TagFilter {
+  // Whether bitset membership alone decides this filter
+  pub fn is_plain(&self) -> bool {
+    self.min_weight.is_none() && self.top_n.is_none()
+  }
+
+  pub fn matches(&self, tag_list: &[(u32, f32)]) -> bool {
+    match tag_list.iter().find(|(id, _)| *id == self.tag_id) {
+      Some((_, weight)) => {
+        self.min_weight.map_or(true, |x| *weight >= x) &&
+        self.top_n.map_or(true, |n| tag_list.iter().filter(|(_, w)| w > weight).count() < n as usize)
+      },
+      None => false,
+    }
+  }
+
+  fn key(&self) -> (u32, Option<u32>, Option<u8>) {
+    (self.tag_id, self.min_weight.map(|x| x.to_bits()), self.top_n)
+  }
+}
+
+impl PartialEq for TagFilter {
+  fn eq(&self, other: &Self) -> bool {
+    self.key() == other.key()
+  }
+}
+
+impl Eq for TagFilter {}
+
+impl PartialOrd for TagFilter {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for TagFilter {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.key().cmp(&other.key())
+  }
+}
+
+impl std::hash::Hash for TagFilter {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.key().hash(state);
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub struct SearchTicket {
   pub keyword_list: Vec<SearchMode<Relation<String>>>,
-  pub tag_list: Vec<Relation<u32>>,
+  pub tag_list: Vec<Relation<TagFilter>>,
   pub year_list: Vec<SearchRange>,
+  pub season_list: Vec<Season>,
+  pub date: SearchRange, // over `encode_date`
   pub rank: SearchRange,
   pub rating_count: SearchRange,
   pub r18: Option<bool>,
+  pub sub_type_list: Vec<PackedSubjectSubtype>,
   pub for_user: Option<u32>,
   pub fav_mode: Option<bool>, // Hako r1
+  pub collection_list: Vec<Relation<CollectionState>>, // Hako r2
+}
+
+impl SearchTicket {
+  // Equivalent ticket with order-independent lists sorted, used as a cache key
+  pub fn canonical(&self) -> SearchTicket {
+    let mut ticket = self.clone();
+    ticket.keyword_list.sort();
+    ticket.tag_list.sort();
+    ticket.tag_list.dedup();
+    ticket.year_list.sort();
+    ticket.year_list.dedup();
+    ticket.season_list.sort();
+    ticket.season_list.dedup();
+    ticket.sub_type_list.sort();
+    ticket.sub_type_list.dedup();
+    ticket.collection_list.sort();
+    ticket.collection_list.dedup();
+    ticket
+  }
+
+  // A subject in `state` passes when it is in one of the included states, if any, and in none of the excluded ones
+  pub fn collection_matches(&self, state: Option<CollectionState>) -> bool {
+    let mut has_include = false;
+    let mut is_included = false;
+    for x in self.collection_list.iter() {
+      match x {
+        Relation::Include(x) => {
+          has_include = true;
+          is_included |= state == Some(*x);
+        },
+        Relation::Exclude(x) => if state == Some(*x) { return false; },
+      }
+    }
+    !has_include || is_included
+  }
+
+  // Char ranges of (`name`, `name_cn`) matching an included keyword, only worth computing for the subjects shown
+  pub fn highlight_span_list(&self, subject: &PackedSubject) -> (SpanList, SpanList) {
+    let include_kwd_list = self.keyword_list.iter().filter_map(|kwd| match kwd {
+      SearchMode::ExactMatch(Relation::Include(x)) | SearchMode::PartialMatch(Relation::Include(x)) => Some(x.chars().collect::<Vec<_>>()),
+      _ => None,
+    }).collect::<Vec<_>>();
+    if include_kwd_list.is_empty() {
+      return (Vec::new(), Vec::new());
+    }
+    let include_kwd_list = include_kwd_list.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
+    (match_keyword_span_list(&include_kwd_list, &subject.name), match_keyword_span_list(&include_kwd_list, &subject.name_cn))
+  }
+
+  // Keyword matching dominates the cost of a search, only these tickets are worth scanning in parallel
+  pub fn is_expensive(&self) -> bool {
+    !self.keyword_list.is_empty()
+  }
+}
+
+// Number of subjects under each filter item
+#[derive(Debug, Clone)]
+pub struct Facet {
+  pub subject_count: u32,
+  pub tag_count_list: Vec<(u32, u32)>, // non-zero counts only, by tag id
+  pub year_count_map: BTreeMap<u16, u32>,
+  pub season_count_list: Vec<u32>,
+  pub sub_type_count_list: Vec<u32>,
+  pub r18_count: u32,
 }
 
+impl Facet {
+  pub fn tag_count(&self, tag_id: u32) -> u32 {
+    self.tag_count_list.binary_search_by_key(&tag_id, |(x, _)| *x).map_or(0, |i| self.tag_count_list[i].1)
+  }
+
+  pub fn year_range_count(&self, range: &SearchRange) -> u32 {
+    self.year_count_map.iter().filter(|(year, _)| range.contains(**year as u32)).map(|(_, count)| *count).sum()
+  }
+}
+
+// Two users side by side, `a_from_b_list` holds favourites of B which A has not favourited, by A's relation
+pub struct UserComparison<'a> {
+  pub fav_jaccard: f32,
+  pub relation_correlation: f32,
+  pub shared_fav_count: usize,
+  pub shared_fav_list: Vec<&'a PackedSubject>,
+  pub a_from_b_list: Vec<(&'a PackedSubject, u16)>,
+  pub b_from_a_list: Vec<(&'a PackedSubject, u16)>,
+}
+
+// Sorted and merged char ranges of a title
+pub type SpanList = Vec<(usize, usize)>;
+
 pub struct SearchResult<'a> {
   pub subject: &'a PackedSubject,
   pub keyword_relative: f32,
@@ -99,26 +325,176 @@ pub fn match_keyword_exact(kwd: &str, target: &str) -> bool {
   target.to_lowercase().contains(kwd)
 }
 
-pub fn match_keyword_partial(a: &[char], b: &[char]) -> f32 {
-  let (n, m) = (a.len(), b.len());
-  let (s, p) = (n + 1, m + 1);
-  let mut c = Vec::new();
-  c.resize(s * p, 0);
-  for i in 0..s {
-    c[i * p] = i;
+// Similarity in 0..=1 of `kwd` to its best matching substring of `target`, 0 below `min_similarity`
+pub fn match_keyword_partial(kwd: &[char], target: &[char], min_similarity: f32) -> f32 {
+  if kwd.is_empty() {
+    return 0.0;
+  }
+  let max_distance = ((1.0 - min_similarity) * kwd.len() as f32).ceil() as usize;
+  match substring_edit_distance(kwd, target, max_distance) {
+    Some(d) => {
+      let similarity = 1.0 - d as f32 / kwd.len() as f32;
+      if similarity >= min_similarity { similarity } else { 0.0 }
+    },
+    None => 0.0,
+  }
+}
+
+// Smallest edit distance between `pattern` and any substring of `text`, None once it exceeds `max_distance`
+pub fn substring_edit_distance(pattern: &[char], text: &[char], max_distance: usize) -> Option<usize> {
+  if pattern.len().saturating_sub(text.len()) > max_distance {
+    return None;
+  }
+  let d = if pattern.len() <= 64 { myers_substring_distance(pattern, text) } else { row_substring_distance(pattern, text, max_distance) };
+  if d <= max_distance { Some(d) } else { None }
+}
+
+// Myers' bit-parallel algorithm, one machine word per text char, pattern must fit in 64 chars
+fn myers_substring_distance(pattern: &[char], text: &[char]) -> usize {
+  if pattern.is_empty() {
+    return 0;
+  }
+  // match mask of every pattern char, sorted by char for binary search
+  let mut peq = pattern.iter().enumerate().map(|(i, c)| (*c, 1u64 << i)).collect::<Vec<_>>();
+  peq.sort_unstable_by_key(|(c, _)| *c);
+  peq.dedup_by(|(c, mask), (last_c, last_mask)| {
+    let is_same = c == last_c;
+    if is_same {
+      *last_mask |= *mask;
+    }
+    is_same
+  });
+  let last = 1u64 << (pattern.len() - 1);
+  let (mut pv, mut mv) = (!0u64, 0u64);
+  let mut score = pattern.len();
+  let mut best = score;
+  for c in text {
+    let eq = peq.binary_search_by_key(c, |(x, _)| *x).map_or(0, |i| peq[i].1);
+    let xv = eq | mv;
+    let xh = ((eq & pv).wrapping_add(pv) ^ pv) | eq;
+    let ph = mv | !(xh | pv);
+    let mh = pv & xh;
+    if ph & last != 0 {
+      score += 1;
+    }
+    else if mh & last != 0 {
+      score -= 1;
+    }
+    // the match may start anywhere in `text`, so no carry into the first row
+    let (ph, mh) = (ph << 1, mh << 1);
+    pv = mh | !(xv | ph);
+    mv = ph & xv;
+    best = best.min(score);
+  }
+  best
+}
+
+// Row by row DP for long patterns, stops as soon as every cell of a row exceeds `max_distance`
+fn row_substring_distance(pattern: &[char], text: &[char], max_distance: usize) -> usize {
+  let mut prev = vec![0; text.len() + 1];
+  let mut curr = vec![0; text.len() + 1];
+  for (i, a) in pattern.iter().enumerate() {
+    curr[0] = i + 1;
+    for (j, b) in text.iter().enumerate() {
+      curr[j + 1] = (prev[j] + (a != b) as usize).min(prev[j + 1] + 1).min(curr[j] + 1);
+    }
+    std::mem::swap(&mut prev, &mut curr);
+    if prev.iter().all(|x| *x > max_distance) {
+      return max_distance + 1;
+    }
   }
-  for i in 1..p {
-    c[i] = i;
+  prev.into_iter().min().unwrap_or(pattern.len())
+}
+
+// Char ranges of `target` containing any keyword case-insensitively, sorted and merged
+pub fn match_keyword_span_list(kwd_list: &[&[char]], target: &str) -> Vec<(usize, usize)> {
+  let mut lowered = Vec::new();
+  let mut origin = Vec::new();
+  for (i, c) in target.chars().enumerate() {
+    for x in c.to_lowercase() {
+      lowered.push(x);
+      origin.push(i);
+    }
+  }
+  let mut span_list = kwd_list.iter().filter(|kwd| !kwd.is_empty() && kwd.len() <= lowered.len()).flat_map(|kwd| {
+    let (lowered, origin) = (&lowered, &origin);
+    lowered.windows(kwd.len()).enumerate().filter(move |(_, w)| w == kwd).map(move |(i, w)| (origin[i], origin[i + w.len() - 1] + 1))
+  }).collect::<Vec<_>>();
+  span_list.sort_unstable();
+  let mut merged_list: Vec<(usize, usize)> = Vec::with_capacity(span_list.len());
+  for (begin, end) in span_list {
+    match merged_list.last_mut() {
+      Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+      _ => merged_list.push((begin, end)),
+    }
   }
-  for i in 0..n {
-    for j in 0..m {
-      let x = c[i * p + (j + 1)] + 1;
-      let y = c[(i + 1) * p + j] + 1;
-      let z = if a[i] == b[j] { c[i * p + j] } else { c[i * p + j] + 1 };
-      c[(i + 1) * p + (j + 1)] = x.min(y).min(z);
+  merged_list
+}
+
+// Saturated term frequency normalised by field length
+fn bm25_term(tf: f32, len: usize, avg_len: f32) -> f32 {
+  if tf <= 0.0 {
+    return 0.0;
+  }
+  tf * (BM25_K1 + 1.0) / (tf + BM25_K1 * (1.0 - BM25_B + BM25_B * len as f32 / avg_len))
+}
+
+// Exact occurrences of the keyword, or a fraction of one for a close fuzzy match
+fn soft_term_frequency(kwd: &str, cached_kwd: &[char], field: &str, cached_field: &[char]) -> f32 {
+  if kwd.is_empty() {
+    return 0.0;
+  }
+  let tf = field.matches(kwd).count();
+  if tf > 0 {
+    return tf as f32;
+  }
+  match_keyword_partial(cached_kwd, cached_field, FUZZY_MIN_SIMILARITY) * FUZZY_WEIGHT
+}
+
+fn average_len<'b, I: Iterator<Item = &'b String>>(iter: I) -> f32 {
+  let (sum, count) = iter.filter(|x| !x.is_empty()).fold((0, 0), |(sum, count), x| (sum + x.chars().count(), count + 1));
+  if count == 0 { 1.0 } else { sum as f32 / count as f32 }
+}
+
+// Ids in both of two sorted lists
+fn sorted_intersection(a: &[u32], b: &[u32]) -> Vec<u32> {
+  let mut l = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  while i < a.len() && j < b.len() {
+    match a[i].cmp(&b[j]) {
+      Ordering::Less => i += 1,
+      Ordering::Greater => j += 1,
+      Ordering::Equal => {
+        l.push(a[i]);
+        i += 1;
+        j += 1;
+      },
     }
   }
-  (1.0 - c[n * p + m] as f32 / a.len().max(b.len()) as f32).max(0.0).min(1.0)
+  l
+}
+
+fn jaccard(n_a: usize, n_b: usize, n_shared: usize) -> f32 {
+  let n_union = n_a + n_b - n_shared;
+  if n_union == 0 { 0.0 } else { n_shared as f32 / n_union as f32 }
+}
+
+// Pearson correlation of two relation rows, relations being ranks this is their Spearman correlation
+fn relation_correlation(a: &[u16], b: &[u16]) -> f32 {
+  let n = a.len() as f64;
+  if a.len() < 2 {
+    return 0.0;
+  }
+  let mean_a = a.iter().map(|x| *x as f64).sum::<f64>() / n;
+  let mean_b = b.iter().map(|x| *x as f64).sum::<f64>() / n;
+  let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+  for (x, y) in a.iter().zip(b.iter()) {
+    let (dx, dy) = (*x as f64 - mean_a, *y as f64 - mean_b);
+    cov += dx * dy;
+    var_a += dx * dx;
+    var_b += dy * dy;
+  }
+  if var_a == 0.0 || var_b == 0.0 { 0.0 } else { (cov / (var_a * var_b).sqrt()) as f32 }
 }
 
 pub fn score_mapper(x: &PackedSubject) -> f32 {
@@ -129,68 +505,118 @@ fn name_mapper(x: &PackedSubject) -> &str {
   if x.name_cn.is_empty() { x.name.as_str() } else { x.name_cn.as_str() }
 }
 
+// Order preserving, an unknown month or day (0) sorts before the first one
+pub fn encode_date(y: u16, m: u8, d: u8) -> u32 {
+  (y as u32) << 16 | (m as u32) << 8 | (d as u32)
+}
+
 fn date_mapper(x: &PackedSubject) -> u32 {
-  (x.air_y as u32) << 16 | (x.air_m as u32) << 8 | (x.air_d as u32)
+  encode_date(x.air_y, x.air_m, x.air_d)
 }
 
-pub fn sort_result_unsearched(mut l: Vec<&PackedSubject>, mode: SortMode, ascent: bool) -> Vec<&PackedSubject> {
-  if ascent {
-    match mode {
-      SortMode::Recommend | SortMode::Relative | SortMode::Rank => l.sort_by(|a, b| score_mapper(a).partial_cmp(&score_mapper(b)).unwrap()),
-      SortMode::Name => l.sort_by(|a, b| name_mapper(a).cmp(&name_mapper(b))),
-      SortMode::Date => l.sort_by(|a, b| date_mapper(a).cmp(&date_mapper(b))),
-      SortMode::FavCount => l.sort_by(|a, b| a.rating_count.cmp(&b.rating_count)),
-    };
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct SortKey {
+  pub mode: SortMode,
+  pub ascent: bool,
+}
+
+fn direct(ord: Ordering, ascent: bool) -> Ordering {
+  if ascent { ord } else { ord.reverse() }
+}
+
+// Total order for scores, NaN is placed last regardless of direction
+fn cmp_score(a: f32, b: f32, ascent: bool) -> Ordering {
+  match (a.is_nan(), b.is_nan()) {
+    (true, true) => Ordering::Equal,
+    (true, false) => Ordering::Greater,
+    (false, true) => Ordering::Less,
+    (false, false) => direct(a.total_cmp(&b), ascent),
   }
-  else {
-    match mode {
-      SortMode::Recommend | SortMode::Relative | SortMode::Rank => l.sort_by(|b, a| score_mapper(a).partial_cmp(&score_mapper(b)).unwrap()),
-      SortMode::Name => l.sort_by(|b, a| name_mapper(a).cmp(&name_mapper(b))),
-      SortMode::Date => l.sort_by(|b, a| date_mapper(a).cmp(&date_mapper(b))),
-      SortMode::FavCount => l.sort_by(|b, a| a.rating_count.cmp(&b.rating_count)),
-    };
+}
+
+fn cmp_subject(a: &PackedSubject, b: &PackedSubject, key: &SortKey) -> Ordering {
+  match key.mode {
+    SortMode::Recommend | SortMode::Relative | SortMode::Rank => cmp_score(score_mapper(a), score_mapper(b), key.ascent),
+    SortMode::Name => direct(name_mapper(a).cmp(name_mapper(b)), key.ascent),
+    SortMode::Date => direct(date_mapper(a).cmp(&date_mapper(b)), key.ascent),
+    SortMode::FavCount => direct(a.rating_count.cmp(&b.rating_count), key.ascent),
   }
-  l
 }
 
-pub fn sort_result(mut l: Vec<SearchResult>, mode: SortMode, ascent: bool) -> Vec<SearchResult> {
-  if ascent {
-    match mode {
-      SortMode::Recommend => l.sort_by(|a, b| a.user_recommend.partial_cmp(&b.user_recommend).unwrap()),
-      SortMode::Relative => l.sort_by(|a, b| a.keyword_relative.partial_cmp(&b.keyword_relative).unwrap()),
-      SortMode::Name => l.sort_by(|a, b| name_mapper(&a.subject).cmp(&name_mapper(&b.subject))),
-      SortMode::Rank => l.sort_by(|a, b| score_mapper(&a.subject).partial_cmp(&score_mapper(&b.subject)).unwrap()),
-      SortMode::Date => l.sort_by(|a, b| date_mapper(&a.subject).cmp(&date_mapper(&b.subject))),
-      SortMode::FavCount => l.sort_by(|a, b| a.subject.rating_count.cmp(&b.subject.rating_count)),
-    };
+fn cmp_result(a: &SearchResult, b: &SearchResult, key: &SortKey) -> Ordering {
+  match key.mode {
+    SortMode::Recommend => cmp_score(a.user_recommend, b.user_recommend, key.ascent),
+    SortMode::Relative => cmp_score(a.keyword_relative, b.keyword_relative, key.ascent),
+    _ => cmp_subject(a.subject, b.subject, key),
   }
-  else {
-    match mode {
-      SortMode::Recommend => l.sort_by(|b, a| a.user_recommend.partial_cmp(&b.user_recommend).unwrap()),
-      SortMode::Relative => l.sort_by(|b, a| a.keyword_relative.partial_cmp(&b.keyword_relative).unwrap()),
-      SortMode::Name => l.sort_by(|b, a| name_mapper(&a.subject).cmp(&name_mapper(&b.subject))),
-      SortMode::Rank => l.sort_by(|b, a| score_mapper(&a.subject).partial_cmp(&score_mapper(&b.subject)).unwrap()),
-      SortMode::Date => l.sort_by(|b, a| date_mapper(&a.subject).cmp(&date_mapper(&b.subject))),
-      SortMode::FavCount => l.sort_by(|b, a| a.subject.rating_count.cmp(&b.subject.rating_count)),
-    };
+}
+
+// `subject_id` is always the final tiebreaker so that page boundaries are stable
+fn cmp_subject_by_key_list(a: &PackedSubject, b: &PackedSubject, key_list: &[SortKey]) -> Ordering {
+  key_list.iter().fold(Ordering::Equal, |acc, key| acc.then_with(|| cmp_subject(a, b, key)))
+  .then_with(|| a.subject_id.cmp(&b.subject_id))
+}
+
+fn cmp_result_by_key_list(a: &SearchResult, b: &SearchResult, key_list: &[SortKey]) -> Ordering {
+  key_list.iter().fold(Ordering::Equal, |acc, key| acc.then_with(|| cmp_result(a, b, key)))
+  .then_with(|| a.subject.subject_id.cmp(&b.subject.subject_id))
+}
+
+// Returns the first `k` items of `l` in sorted order without sorting the rest
+fn top_k_by<T, F>(mut l: Vec<T>, k: usize, cmp: F) -> Vec<T>
+where
+  F: Fn(&T, &T) -> Ordering,
+{
+  if k == 0 {
+    l.clear();
+  }
+  else if k < l.len() {
+    l.select_nth_unstable_by(k - 1, &cmp);
+    l.truncate(k);
   }
+  l.sort_unstable_by(cmp);
   l
 }
 
+pub fn top_k_result_unsearched<'a>(l: Vec<&'a PackedSubject>, key_list: &[SortKey], k: usize) -> Vec<&'a PackedSubject> {
+  top_k_by(l, k, |a, b| cmp_subject_by_key_list(a, b, key_list))
+}
+
+pub fn top_k_result<'a>(l: Vec<SearchResult<'a>>, key_list: &[SortKey], k: usize) -> Vec<SearchResult<'a>> {
+  top_k_by(l, k, |a, b| cmp_result_by_key_list(a, b, key_list))
+}
+
+// Sort modes which give distinct orders when no search is performed
+const STATIC_SORT_MODE_LIST: [SortMode; 4] = [SortMode::Rank, SortMode::Name, SortMode::Date, SortMode::FavCount];
+
+fn static_sort_order_slot(key: &SortKey) -> usize {
+  let i = match key.mode {
+    SortMode::Recommend | SortMode::Relative | SortMode::Rank => 0,
+    SortMode::Name => 1,
+    SortMode::Date => 2,
+    SortMode::FavCount => 3,
+  };
+  i * 2 + key.ascent as usize
+}
+
+impl SearchRange {
+  pub fn contains(&self, x: u32) -> bool {
+    match self {
+      SearchRange::Range(begin, end) => (*begin..*end).contains(&x),
+      SearchRange::RangeTo(end) => (..*end).contains(&x),
+      SearchRange::RangeFrom(begin) => (*begin..).contains(&x),
+      SearchRange::RangeFull => true,
+    }
+  }
+}
+
 pub trait SearchRangeList {
   fn range_contains(&self, x: u32) -> bool;
 }
 
 impl SearchRangeList for Vec<SearchRange> {
   fn range_contains(&self, x: u32) -> bool {
-    self.iter().any(|year_range| {
-      match year_range {
-        SearchRange::Range(begin, end) => (*begin..*end).contains(&x),
-        SearchRange::RangeTo(end) => (..*end).contains(&x),
-        SearchRange::RangeFrom(begin) => (*begin..).contains(&x),
-        SearchRange::RangeFull => true,
-      }
-    })
+    self.iter().any(|year_range| year_range.contains(x))
   }
 }
 
@@ -200,8 +626,29 @@ impl <'a> DB<'a> {
     P: AsRef<Path>
   {
     eprintln!("* Load persistence_table");
-    let persistence_table = bincode::deserialize_from::<_, PackedDatabasePersistenceTable>(BufReader::new(std::fs::OpenOptions::new().read(true).open(path.as_ref()).unwrap())).unwrap();
+    let mut persistence_table = bincode::deserialize_from::<_, PackedDatabasePersistenceTable>(BufReader::new(std::fs::OpenOptions::new().read(true).open(path.as_ref()).unwrap())).unwrap();
     assert_eq!(persistence_table.user_id_list.len(), persistence_table.user_username_list.len());
+    assert_eq!(persistence_table.user_id_list.len(), persistence_table.user_collection_list.len());
+
+    eprintln!("* Validate subject_packed_list");
+    let invalid_score_list = persistence_table.subject_packed_list.iter_mut().filter(|x| !(0.0..=10.0).contains(&x.score)).map(|x| {
+      x.score = 0.0;
+      x.subject_id
+    }).collect::<Vec<_>>();
+    if !invalid_score_list.is_empty() {
+      eprintln!("Invalid score reset to 0 for {} subject(s): {:?}", invalid_score_list.len(), invalid_score_list);
+    }
+
+    // favourites are intersected by merging
+    for fav_list in persistence_table.user_fav_list.iter_mut() {
+      fav_list.sort_unstable();
+      fav_list.dedup();
+    }
+    // collection states are looked up by subject_id
+    for collection_list in persistence_table.user_collection_list.iter_mut() {
+      collection_list.sort_unstable_by_key(|x| x.0);
+      collection_list.dedup_by_key(|x| x.0);
+    }
 
     eprintln!("* Load map_table");
     let expected_bytes_count = persistence_table.subject_packed_list.len() * persistence_table.user_id_list.len() * core::mem::size_of::<u16>();
@@ -216,11 +663,110 @@ impl <'a> DB<'a> {
     assert_eq!(map_table_handle.len(), expected_bytes_count);
     let map_table = unsafe { core::slice::from_raw_parts(map_table_handle.as_ptr() as *const u16, expected_bytes_count / core::mem::size_of::<u16>()) };
 
+    eprintln!("* Build static_sort_order_table");
+    let static_sort_order_table = STATIC_SORT_MODE_LIST.iter().flat_map(|mode| [false, true].iter().map(move |ascent| SortKey { mode: *mode, ascent: *ascent })).map(|key| {
+      let subject_list = &persistence_table.subject_packed_list;
+      let mut order = (0..subject_list.len() as u32).collect::<Vec<_>>();
+      order.sort_unstable_by(|a, b| cmp_subject_by_key_list(&subject_list[*a as usize], &subject_list[*b as usize], &[key]));
+      order
+    }).collect::<Vec<_>>();
+
+    eprintln!("* Build filter bitsets");
+    let n_subject = persistence_table.subject_packed_list.len();
+    let mut tag_bitset_list = vec![Bitset::new(n_subject); persistence_table.tag_name_list.len()];
+    let mut year_bitset_map = BTreeMap::new();
+    let mut sub_type_bitset_list = vec![Bitset::new(n_subject); PackedSubjectSubtype::Movie as usize + 1];
+    let mut season_bitset_list = vec![Bitset::new(n_subject); Season::Autumn as usize + 1];
+    let mut r18_bitset = Bitset::new(n_subject);
+    let mut tag_count_list = vec![0u32; persistence_table.tag_name_list.len()];
+    for (i, subject) in persistence_table.subject_packed_list.iter().enumerate() {
+      for (tag_id, _) in subject.tag_list.iter() {
+        tag_bitset_list[*tag_id as usize].insert(i);
+        tag_count_list[*tag_id as usize] += 1;
+      }
+      year_bitset_map.entry(subject.air_y).or_insert_with(|| Bitset::new(n_subject)).insert(i);
+      if let Some(season) = Season::from_month(subject.air_m) {
+        season_bitset_list[season as usize].insert(i);
+      }
+      sub_type_bitset_list[subject.sub_type as usize].insert(i);
+      if subject.is_r18 {
+        r18_bitset.insert(i);
+      }
+    }
+
+    let mut tag_frequency_order = (0..tag_count_list.len() as u32).collect::<Vec<_>>();
+    tag_frequency_order.sort_by_key(|x| (std::cmp::Reverse(tag_count_list[*x as usize]), *x));
+
+    eprintln!("* Build title_prefix_index");
+    let mut title_prefix_index = Vec::new();
+    for (i, subject) in persistence_table.subject_packed_list.iter().enumerate() {
+      for title in [&subject.name, &subject.name_cn].iter().filter(|x| !x.is_empty()) {
+        let title = title.to_lowercase();
+        // every word start is indexed so that `star` finds `sword star`
+        for (j, _) in title.char_indices().filter(|(j, _)| *j == 0 || title[..*j].ends_with(char::is_whitespace)) {
+          title_prefix_index.push((title[j..].to_string(), i as u32));
+        }
+      }
+    }
+    title_prefix_index.sort_unstable();
+
+    eprintln!("* Build subject_fav_user_index");
+    let mut subject_fav_user_index = vec![Vec::new(); persistence_table.subject_packed_list.len()];
+    for (i_user, fav_list) in persistence_table.user_fav_list.iter().enumerate() {
+      for subject_id in fav_list.iter() {
+        if let Ok(i) = persistence_table.subject_packed_list.binary_search_by_key(subject_id, |x| x.subject_id) {
+          subject_fav_user_index[i].push(i_user as u32);
+        }
+      }
+    }
+
+    eprintln!("* Build username_index");
+    let mut username_index = HashMap::with_capacity(persistence_table.user_username_list.len());
+    for (i, username) in persistence_table.user_username_list.iter().enumerate() {
+      username_index.entry(username.to_lowercase()).or_insert(i as u32);
+    }
+    let mut username_prefix_index = persistence_table.user_username_list.iter().enumerate().map(|(i, x)| (x.to_lowercase(), i as u32)).collect::<Vec<_>>();
+    username_prefix_index.sort_unstable();
+    let mut username_length_order = username_prefix_index.iter().enumerate().map(|(j, (x, _))| (x.chars().count(), j as u32)).collect::<Vec<_>>();
+    username_length_order.sort_unstable();
+
+    let mut excluded_user_list = std::mem::take(&mut persistence_table.excluded_user_list);
+    excluded_user_list.sort_unstable();
+    let mut excluded_username_index = HashMap::with_capacity(excluded_user_list.len());
+    for (i, (_, username)) in excluded_user_list.iter().enumerate() {
+      excluded_username_index.entry(username.to_lowercase()).or_insert(i as u32);
+    }
+
+    eprintln!("* Build title statistics");
+    let avg_title_len = (
+      average_len(persistence_table.subject_packed_list.iter().map(|x| &x.name_cn)),
+      average_len(persistence_table.subject_packed_list.iter().map(|x| &x.name)),
+    );
+    let max_rating_count = persistence_table.subject_packed_list.iter().map(|x| x.rating_count).max().unwrap_or(0);
+
     eprintln!("* Load finished");
     DB {
       persistence_table,
       map_table_handle,
       map_table: map_table,
+      static_sort_order_table,
+      tag_bitset_list,
+      tag_count_list,
+      tag_frequency_order,
+      year_bitset_map,
+      season_bitset_list,
+      sub_type_bitset_list,
+      r18_bitset,
+      title_prefix_index,
+      username_index,
+      username_prefix_index,
+      username_length_order,
+      excluded_user_list,
+      excluded_username_index,
+      subject_fav_user_index,
+      avg_title_len,
+      max_rating_count,
+      generation: DB_GENERATION.fetch_add(1, atomic::Ordering::Relaxed),
     }
   }
 
@@ -229,6 +775,11 @@ impl <'a> DB<'a> {
     self.persistence_table.db_date
   }
 
+  // Distinguishes every opened DB, so cached query results never outlive their DB
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
   pub fn d_fac(&self) -> [f32; 2] {
     self.persistence_table.d_fac
   }
@@ -241,23 +792,105 @@ impl <'a> DB<'a> {
     self.persistence_table.subject_packed_list.iter()
   }
 
-  /*pub fn get_subject_by_id(&self, subject_id: u32) -> Option<&PackedSubject> {
+  // Returns `n_take` subjects after skipping `n_skip`, sorted by `key_list` over the whole catalogue
+  pub fn sorted_subject_page(&self, key_list: &[SortKey], n_skip: usize, n_take: usize) -> Vec<&PackedSubject> {
+    let subject_list = &self.persistence_table.subject_packed_list;
+    if key_list.len() == 1 {
+      self.static_sort_order_table[static_sort_order_slot(&key_list[0])].iter().skip(n_skip).take(n_take).map(|i| &subject_list[*i as usize]).collect()
+    }
+    else {
+      top_k_result_unsearched(subject_list.iter().collect(), key_list, n_skip + n_take).into_iter().skip(n_skip).collect()
+    }
+  }
+
+  pub fn get_subject_by_id(&self, subject_id: u32) -> Option<&PackedSubject> {
     match self.persistence_table.subject_packed_list.binary_search_by_key(&subject_id, |x| x.subject_id) {
       Ok(i) => Some(self.persistence_table.subject_packed_list.get(i).unwrap()),
       _ => None,
     }
-  }*/
+  }
+
+  // Subjects sharing a tag with `subject`, by cosine similarity of their tag weights scaled by idf, most similar first
+  pub fn similar_subjects(&self, subject: &PackedSubject, k: usize) -> Vec<(&PackedSubject, f32)> {
+    let subject_list = &self.persistence_table.subject_packed_list;
+    let n_subject = subject_list.len();
+    let idf = |tag_id: u32| ((n_subject as f32 + 1.0) / (self.tag_count_list[tag_id as usize] as f32 + 1.0)).ln();
+    let norm = |tag_list: &[(u32, f32)]| tag_list.iter().map(|(tag_id, weight)| (weight * idf(*tag_id)).powi(2)).sum::<f32>().sqrt();
+    let subject_norm = norm(&subject.tag_list);
+    if subject_norm == 0.0 {
+      return Vec::new();
+    }
+
+    let mut mask = Bitset::new(n_subject);
+    for (tag_id, _) in subject.tag_list.iter() {
+      mask.or_with(&self.tag_bitset_list[*tag_id as usize]);
+    }
+    let candidate_list = mask.iter().map(|i| &subject_list[i]).filter(|x| x.subject_id != subject.subject_id).filter_map(|x| {
+      let dot = x.tag_list.iter().filter_map(|(tag_id, weight)| {
+        subject.tag_list.iter().find(|(y, _)| y == tag_id).map(|(_, subject_weight)| weight * subject_weight * idf(*tag_id).powi(2))
+      }).sum::<f32>();
+      let candidate_norm = norm(&x.tag_list);
+      if dot > 0.0 && candidate_norm > 0.0 { Some((x, dot / (subject_norm * candidate_norm))) } else { None }
+    }).collect::<Vec<_>>();
+    top_k_by(candidate_list, k, |a, b| cmp_score(a.1, b.1, false).then_with(|| cmp_subject_by_key_list(a.0, b.0, &[SortKey { mode: SortMode::Rank, ascent: false }])))
+  }
 
+  // Case insensitive, the first user wins when two usernames only differ in case
   pub fn get_user_id_by_username(&self, username: &str) -> Option<u32> {
-    self.persistence_table.user_username_list.iter().enumerate().find(|(_, v)| v.to_lowercase() == username).map(|(i, _)| *self.persistence_table.user_id_list.get(i).unwrap())
+    self.username_index.get(&username.to_lowercase()).map(|i| self.persistence_table.user_id_list[*i as usize])
   }
 
-  /*pub fn get_user_username_by_id(&self, user_id: u32) -> Option<&str> {
+  pub fn get_user_username_by_id(&self, user_id: u32) -> Option<&str> {
     match self.persistence_table.user_id_list.binary_search(&user_id) {
       Ok(i) => Some(self.persistence_table.user_username_list.get(i).unwrap().as_str()),
       _ => None,
     }
-  }*/
+  }
+
+  // (user_id, username) of a user the packer excluded, looked up by UID or case insensitive username
+  pub fn get_excluded_user(&self, user_id: Option<u32>, username: &str) -> Option<(u32, &str)> {
+    let i = match user_id {
+      Some(user_id) => self.excluded_user_list.binary_search_by_key(&user_id, |x| x.0).ok()?,
+      None => *self.excluded_username_index.get(&username.to_lowercase())? as usize,
+    };
+    self.excluded_user_list.get(i).map(|(id, name)| (*id, name.as_str()))
+  }
+
+  // Usernames starting with `query` (shortest first), followed by the closest fuzzy matches among names of a similar length
+  pub fn suggest_username(&self, query: &str, n: usize) -> Vec<&str> {
+    let query = query.to_lowercase();
+    if query.is_empty() || n == 0 {
+      return Vec::new();
+    }
+    let username_list = &self.persistence_table.user_username_list;
+    let i_begin = self.username_prefix_index.partition_point(|(x, _)| x.as_str() < query.as_str());
+    let prefix_list = self.username_prefix_index[i_begin..].iter().take_while(|(x, _)| x.starts_with(&query)).map(|(_, i)| username_list[*i as usize].as_str()).collect::<Vec<_>>();
+    let mut suggestion_list = top_k_by(prefix_list, n, |a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    if suggestion_list.len() < n {
+      // names further than `max_distance` chars from the query length cannot reach FUZZY_MIN_SIMILARITY, nearest lengths go first
+      let query_cache = query.chars().collect::<Vec<_>>();
+      let max_distance = ((1.0 - FUZZY_MIN_SIMILARITY) * query_cache.len() as f32).ceil() as usize;
+      let length_range = |len: usize| {
+        let i_begin = self.username_length_order.partition_point(|(x, _)| *x < len);
+        let i_end = self.username_length_order.partition_point(|(x, _)| *x <= len);
+        &self.username_length_order[i_begin..i_end]
+      };
+      let candidate_list = (0..=max_distance).flat_map(|d| {
+        let shorter = if d > 0 && d <= query_cache.len() { length_range(query_cache.len() - d) } else { &[] };
+        length_range(query_cache.len() + d).iter().chain(shorter.iter())
+      }).take(USERNAME_FUZZY_SCAN_SIZE).filter_map(|(_, j)| {
+        let (name, i) = &self.username_prefix_index[*j as usize];
+        if name.starts_with(&query) {
+          return None;
+        }
+        let relative = match_keyword_partial(&query_cache, &name.chars().collect::<Vec<_>>(), FUZZY_MIN_SIMILARITY);
+        if relative > 0.0 { Some((username_list[*i as usize].as_str(), relative)) } else { None }
+      }).collect::<Vec<_>>();
+      let fuzzy_list = top_k_by(candidate_list, n - suggestion_list.len(), |a, b| cmp_score(a.1, b.1, false).then_with(|| a.0.len().cmp(&b.0.len())).then_with(|| a.0.cmp(b.0)));
+      suggestion_list.extend(fuzzy_list.into_iter().map(|x| x.0));
+    }
+    suggestion_list
+  }
 
   pub fn get_user_subject_relation(&self, user_id: u32, subject_id: u32) -> Option<u16> {
     match self.persistence_table.user_id_list.binary_search(&user_id) {
@@ -283,6 +916,106 @@ impl <'a> DB<'a> {
     }
   }
 
+  pub fn get_user_fav_list(&self, user_id: u32) -> Option<&[u32]> { // Hako r1
+    match self.persistence_table.user_id_list.binary_search(&user_id) {
+      Ok(i_user) => Some(self.persistence_table.user_fav_list[i_user].as_slice()),
+      _ => None,
+    }
+  }
+
+  pub fn get_user_collection_state(&self, user_id: u32, subject_id: u32) -> Option<CollectionState> { // Hako r2
+    let i_user = self.persistence_table.user_id_list.binary_search(&user_id).ok()?;
+    let collection_list = &self.persistence_table.user_collection_list[i_user];
+    collection_list.binary_search_by_key(&subject_id, |x| x.0).ok().map(|i| collection_list[i].1)
+  }
+
+  // The `k` subjects `user_id` has not favourited with the highest relation to them, along with that relation
+  pub fn recommend_for_user(&self, user_id: u32, k: usize) -> Vec<(&PackedSubject, u16)> {
+    let i_user = match self.persistence_table.user_id_list.binary_search(&user_id) {
+      Ok(i) => i,
+      _ => return Vec::new(),
+    };
+    let fav_list = &self.persistence_table.user_fav_list[i_user];
+    let candidate_list = self.persistence_table.subject_packed_list.iter().zip(self.relation_row(i_user).iter()).filter(|(x, _)| fav_list.binary_search(&x.subject_id).is_err()).map(|(x, relation)| (x, *relation)).collect::<Vec<_>>();
+    top_k_by(candidate_list, k, |a, b| b.1.cmp(&a.1).then_with(|| a.0.subject_id.cmp(&b.0.subject_id)))
+  }
+
+  // Relations of the user at `i_user` to every subject, indexed like `subject_packed_list`
+  fn relation_row(&self, i_user: usize) -> &[u16] {
+    let n_subject = self.persistence_table.subject_packed_list.len();
+    &self.map_table[i_user * n_subject..(i_user + 1) * n_subject]
+  }
+
+  // Favourite overlap and relation correlation of two users, their `k` best ranked shared favourites, and the `k` favourites of each the other ranks highest
+  pub fn compare_users(&self, user_a: u32, user_b: u32, k: usize) -> Option<UserComparison<'_>> {
+    let user_id_list = &self.persistence_table.user_id_list;
+    let (i_a, i_b) = (user_id_list.binary_search(&user_a).ok()?, user_id_list.binary_search(&user_b).ok()?);
+    let (fav_a, fav_b) = (&self.persistence_table.user_fav_list[i_a], &self.persistence_table.user_fav_list[i_b]);
+    let (row_a, row_b) = (self.relation_row(i_a), self.relation_row(i_b));
+    let subject_list = &self.persistence_table.subject_packed_list;
+    let shared_list = sorted_intersection(fav_a, fav_b);
+
+    let pick = |fav_self: &[u32], fav_other: &[u32], row_self: &[u16]| {
+      let candidate_list = fav_other.iter().filter(|x| fav_self.binary_search(x).is_err()).filter_map(|x| {
+        subject_list.binary_search_by_key(x, |y| y.subject_id).ok().map(|i| (&subject_list[i], row_self[i]))
+      }).collect::<Vec<_>>();
+      top_k_by(candidate_list, k, |a, b| b.1.cmp(&a.1).then_with(|| a.0.subject_id.cmp(&b.0.subject_id)))
+    };
+    Some(UserComparison {
+      fav_jaccard: jaccard(fav_a.len(), fav_b.len(), shared_list.len()),
+      relation_correlation: relation_correlation(row_a, row_b),
+      shared_fav_count: shared_list.len(),
+      shared_fav_list: top_k_result_unsearched(shared_list.iter().filter_map(|x| self.get_subject_by_id(*x)).collect(), &[SortKey { mode: SortMode::Rank, ascent: false }], k),
+      a_from_b_list: pick(fav_a, fav_b, row_a),
+      b_from_a_list: pick(fav_b, fav_a, row_b),
+    })
+  }
+
+  // The `k` users closest to `user_id` with their scores, the favourite Jaccard index scaled by how well their relations correlate
+  pub fn similar_users(&self, user_id: u32, k: usize) -> Vec<(u32, f32)> {
+    let user_id_list = &self.persistence_table.user_id_list;
+    let i_user = match user_id_list.binary_search(&user_id) {
+      Ok(i) => i,
+      _ => return Vec::new(),
+    };
+    let fav_list = &self.persistence_table.user_fav_list[i_user];
+    let mut shared_count_list = vec![0u32; user_id_list.len()];
+    for subject_id in fav_list.iter() {
+      if let Ok(i) = self.persistence_table.subject_packed_list.binary_search_by_key(subject_id, |x| x.subject_id) {
+        for i_other in self.subject_fav_user_index[i].iter() {
+          shared_count_list[*i_other as usize] += 1;
+        }
+      }
+    }
+    shared_count_list[i_user] = 0;
+
+    let candidate_list = shared_count_list.iter().enumerate().filter(|(_, n_shared)| **n_shared > 0).map(|(i_other, n_shared)| {
+      (i_other, jaccard(fav_list.len(), self.persistence_table.user_fav_list[i_other].len(), *n_shared as usize))
+    }).collect::<Vec<_>>();
+    let candidate_list = top_k_by(candidate_list, SIMILAR_USER_CANDIDATE_SIZE.max(k), |a, b| cmp_score(a.1, b.1, false).then_with(|| a.0.cmp(&b.0)));
+    let row = self.relation_row(i_user);
+    let scored_list = candidate_list.into_par_iter().map(|(i_other, fav_jaccard)| {
+      (i_other, fav_jaccard * (1.0 + relation_correlation(row, self.relation_row(i_other))) / 2.0)
+    }).collect::<Vec<_>>();
+    top_k_by(scored_list, k, |a, b| cmp_score(a.1, b.1, false).then_with(|| a.0.cmp(&b.0))).into_iter().map(|(i_other, score)| (user_id_list[i_other], score)).collect()
+  }
+
+  // Tags over the subjects `user_id` favourited, each subject spreading a weight of 1 over its tags, heaviest first
+  pub fn user_tag_profile(&self, user_id: u32) -> Vec<(u32, f32)> {
+    let mut weight_list = vec![0.0f32; self.persistence_table.tag_name_list.len()];
+    for subject in self.get_user_fav_list(user_id).unwrap_or(&[]).iter().filter_map(|x| self.get_subject_by_id(*x)) {
+      let total = subject.tag_list.iter().map(|(_, weight)| weight).sum::<f32>();
+      if total > 0.0 {
+        for (tag_id, weight) in subject.tag_list.iter() {
+          weight_list[*tag_id as usize] += weight / total;
+        }
+      }
+    }
+    let mut profile = weight_list.into_iter().enumerate().filter(|(_, weight)| *weight > 0.0).map(|(i, weight)| (i as u32, weight)).collect::<Vec<_>>();
+    profile.sort_by(|a, b| cmp_score(a.1, b.1, false).then_with(|| a.0.cmp(&b.0)));
+    profile
+  }
+
   pub fn get_tag_id_by_name(&self, name: &str) -> Option<u32> {
     match self.persistence_table.tag_name_list.binary_search_by(|x| x.to_lowercase().as_str().cmp(&name.to_lowercase())) {
       Ok(i) => Some(i as u32),
@@ -290,6 +1023,136 @@ impl <'a> DB<'a> {
     }
   }
 
+  // Subjects passing the r18, tag, year and sub_type filters of `ticket`
+  fn compile_ticket(&self, ticket: &SearchTicket) -> Bitset {
+    let n_subject = self.persistence_table.subject_packed_list.len();
+    let mut mask = Bitset::full(n_subject);
+    match ticket.r18 {
+      Some(true) => mask.and_with(&self.r18_bitset),
+      Some(false) => mask.and_not_with(&self.r18_bitset),
+      None => {},
+    }
+    for tag in ticket.tag_list.iter() {
+      // weighted filters are narrowed further in match_subject
+      match tag {
+        Relation::Include(x) => match self.tag_bitset_list.get(x.tag_id as usize) {
+          Some(tag_bitset) => mask.and_with(tag_bitset),
+          None => { return Bitset::new(n_subject); },
+        },
+        Relation::Exclude(x) => if let Some(tag_bitset) = self.tag_bitset_list.get(x.tag_id as usize) {
+          if x.is_plain() {
+            mask.and_not_with(tag_bitset);
+          }
+        },
+      }
+    }
+    if !ticket.year_list.is_empty() {
+      let mut year_mask = Bitset::new(n_subject);
+      for (_, year_bitset) in self.year_bitset_map.iter().filter(|(year, _)| ticket.year_list.range_contains(**year as u32)) {
+        year_mask.or_with(year_bitset);
+      }
+      mask.and_with(&year_mask);
+    }
+    if !ticket.season_list.is_empty() {
+      let mut season_mask = Bitset::new(n_subject);
+      for season in ticket.season_list.iter() {
+        season_mask.or_with(&self.season_bitset_list[*season as usize]);
+      }
+      mask.and_with(&season_mask);
+    }
+    if !ticket.sub_type_list.is_empty() {
+      let mut sub_type_mask = Bitset::new(n_subject);
+      for sub_type in ticket.sub_type_list.iter() {
+        sub_type_mask.or_with(&self.sub_type_bitset_list[*sub_type as usize]);
+      }
+      mask.and_with(&sub_type_mask);
+    }
+    mask
+  }
+
+  pub fn facet_count<'b, I>(&self, subject_iter: I) -> Facet
+  where
+    I: Iterator<Item = &'b PackedSubject>,
+  {
+    let mut tag_count_list = vec![0u32; self.persistence_table.tag_name_list.len()];
+    let mut facet = Facet {
+      subject_count: 0,
+      tag_count_list: Vec::new(),
+      year_count_map: BTreeMap::new(),
+      season_count_list: vec![0; Season::Autumn as usize + 1],
+      sub_type_count_list: vec![0; PackedSubjectSubtype::Movie as usize + 1],
+      r18_count: 0,
+    };
+    for subject in subject_iter {
+      facet.subject_count += 1;
+      for (tag_id, _) in subject.tag_list.iter() {
+        tag_count_list[*tag_id as usize] += 1;
+      }
+      *facet.year_count_map.entry(subject.air_y).or_insert(0) += 1;
+      if let Some(season) = Season::from_month(subject.air_m) {
+        facet.season_count_list[season as usize] += 1;
+      }
+      facet.sub_type_count_list[subject.sub_type as usize] += 1;
+      if subject.is_r18 {
+        facet.r18_count += 1;
+      }
+    }
+    facet.tag_count_list = tag_count_list.into_iter().enumerate().filter(|(_, count)| *count > 0).map(|(i, count)| (i as u32, count)).collect();
+    facet
+  }
+
+  // Subjects with a title word starting with `query`, most popular first
+  pub fn suggest_subject(&self, query: &str, n: usize) -> Vec<&PackedSubject> {
+    let query = query.trim().to_lowercase();
+    if query.is_empty() || n == 0 {
+      return Vec::new();
+    }
+    let i_begin = self.title_prefix_index.partition_point(|(x, _)| x.as_str() < query.as_str());
+    let mut id_list = self.title_prefix_index[i_begin..].iter().take_while(|(x, _)| x.starts_with(&query)).map(|(_, i)| *i).collect::<Vec<_>>();
+    id_list.sort_unstable();
+    id_list.dedup();
+    let subject_list = &self.persistence_table.subject_packed_list;
+    let key_list = [SortKey { mode: SortMode::Rank, ascent: false }];
+    top_k_result_unsearched(id_list.into_iter().map(|i| &subject_list[i as usize]).collect(), &key_list, n)
+  }
+
+  pub fn get_tag_name_by_id(&self, tag_id: u32) -> Option<&str> {
+    self.persistence_table.tag_name_list.get(tag_id as usize).map(|x| x.as_str())
+  }
+
+  pub fn get_tag_count_by_id(&self, tag_id: u32) -> u32 {
+    self.tag_count_list.get(tag_id as usize).cloned().unwrap_or(0)
+  }
+
+  // Tags starting with `query` ordered by frequency, followed by the closest fuzzy matches
+  pub fn suggest_tag(&self, query: &str, n: usize) -> Vec<u32> {
+    let query = query.to_lowercase();
+    if query.is_empty() || n == 0 {
+      return Vec::new();
+    }
+    let tag_name_list = &self.persistence_table.tag_name_list;
+    let i_begin = tag_name_list.partition_point(|x| x.to_lowercase() < query);
+    let mut prefix_list = (i_begin..tag_name_list.len()).take_while(|i| tag_name_list[*i].to_lowercase().starts_with(&query)).map(|i| i as u32).collect::<Vec<_>>();
+    prefix_list.sort_by_key(|x| (std::cmp::Reverse(self.tag_count_list[*x as usize]), *x));
+    prefix_list.truncate(n);
+    if prefix_list.len() < n {
+      let query_cache = query.chars().collect::<Vec<_>>();
+      let mut fuzzy_list = tag_name_list.iter().enumerate().filter(|(i, _)| !prefix_list.contains(&(*i as u32))).filter_map(|(i, name)| {
+        let name = name.to_lowercase();
+        let relative = if name.contains(&query) { 1.0 } else { match_keyword_partial(&query_cache, &name.chars().collect::<Vec<_>>(), FUZZY_MIN_SIMILARITY) };
+        if relative > 0.0 { Some((i as u32, relative)) } else { None }
+      }).collect::<Vec<_>>();
+      fuzzy_list.sort_by(|a, b| cmp_score(a.1, b.1, false).then_with(|| self.tag_count_list[b.0 as usize].cmp(&self.tag_count_list[a.0 as usize])));
+      prefix_list.extend(fuzzy_list.into_iter().take(n - prefix_list.len()).map(|x| x.0));
+    }
+    prefix_list
+  }
+
+  // Tag ids ordered by the number of subjects carrying them, most frequent first
+  pub fn frequent_tag_iter(&self) -> impl Iterator<Item = u32> + '_ {
+    self.tag_frequency_order.iter().cloned()
+  }
+
   pub fn search_by_ticket(&self, ticket: &SearchTicket) -> Vec<SearchResult> {
     let cached_ticket_keyword = ticket.keyword_list.iter().map(|x| {
       match x {
@@ -303,118 +1166,250 @@ impl <'a> DB<'a> {
         },
       }.chars().collect::<Vec<_>>()
     }).collect::<Vec<_>>();
-    self.persistence_table.subject_packed_list.iter().filter_map(|subject| {
-      // r18
-      match ticket.r18 {
-        Some(x) => {
-          if x != subject.is_r18 {
-            return None;
-          }
-        }
-        None => {}
-      };
+    let subject_list = &self.persistence_table.subject_packed_list;
+    let mask = self.compile_ticket(ticket);
+    let matched_list = if ticket.is_expensive() {
+      let candidate_list = mask.iter().collect::<Vec<_>>();
+      candidate_list.par_chunks(SEARCH_CHUNK_SIZE).flat_map_iter(|chunk| {
+        chunk.iter().filter_map(|i| self.match_subject(ticket, &cached_ticket_keyword, &subject_list[*i]))
+      }).collect::<Vec<_>>()
+    }
+    else {
+      mask.iter().filter_map(|i| self.match_subject(ticket, &cached_ticket_keyword, &subject_list[i])).collect::<Vec<_>>()
+    };
+    if ticket.keyword_list.is_empty() {
+      matched_list.into_iter().map(|(x, _)| x).collect()
+    }
+    else {
+      self.score_relevance(ticket, matched_list)
+    }
+  }
 
-      // tag
-      if !ticket.tag_list.iter().all(|tag| {
-        match tag {
-          Relation::Include(x) => subject.tag_list.iter().find(|(id, _)| id == x).is_some(),
-          Relation::Exclude(x) => subject.tag_list.iter().find(|(id, _)| id == x).is_none(),
-        }
-      }) {
-        return None;
-      }
-      // year
-      if !(ticket.year_list.is_empty() || ticket.year_list.range_contains(subject.air_y as u32)) {
+  // idf needs the document frequency over every candidate, so term scores from `match_subject` are only combined here.
+  // Subjects matching none of the included keywords are dropped.
+  fn score_relevance<'b>(&self, ticket: &SearchTicket, matched_list: Vec<(SearchResult<'b>, Vec<(f32, bool)>)>) -> Vec<SearchResult<'b>> {
+    let n = matched_list.len() as f32;
+    let idf_list = (0..ticket.keyword_list.len()).map(|k| {
+      let df = matched_list.iter().filter(|(_, term_list)| term_list[k].1).count() as f32;
+      (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+    }).collect::<Vec<_>>();
+    let has_include = ticket.keyword_list.iter().any(|x| matches!(x, SearchMode::ExactMatch(Relation::Include(_)) | SearchMode::PartialMatch(Relation::Include(_))));
+    let rating_count_norm = (1.0 + self.max_rating_count as f32).ln().max(1.0);
+    matched_list.into_iter().filter_map(|(mut result, term_list)| {
+      // penalties from excluded keywords are not idf weighted
+      let text_score = term_list.iter().zip(idf_list.iter()).map(|((score, _), idf)| if *score > 0.0 { score * idf } else { *score }).sum::<f32>();
+      if has_include && text_score <= 0.0 {
         return None;
       }
-      // rank
-      if !match &ticket.rank {
-        SearchRange::Range(begin, end) => (*begin..*end).contains(&subject.rank),
-        SearchRange::RangeTo(end) => (..*end).contains(&subject.rank),
-        SearchRange::RangeFrom(begin) => (*begin..).contains(&subject.rank),
-        SearchRange::RangeFull => true,
-      } {
-        return None;
+      let prior = POPULARITY_WEIGHT * (1.0 + result.subject.rating_count as f32).ln() / rating_count_norm;
+      result.keyword_relative = text_score + prior;
+      Some(result)
+    }).collect()
+  }
+
+  // Applies the per-subject filters, and for each keyword returns its boosted BM25 term score (negative for an excluded keyword) and whether it occurred exactly
+  fn match_subject<'b>(&'b self, ticket: &SearchTicket, cached_ticket_keyword: &[Vec<char>], subject: &'b PackedSubject) -> Option<(SearchResult<'b>, Vec<(f32, bool)>)> {
+    // tag weight
+    if !ticket.tag_list.iter().all(|tag| {
+      match tag {
+        Relation::Include(x) => x.is_plain() || x.matches(&subject.tag_list),
+        Relation::Exclude(x) => x.is_plain() || !x.matches(&subject.tag_list),
       }
-      // fav
-      if !match &ticket.rating_count {
-        SearchRange::Range(begin, end) => (*begin..*end).contains(&subject.rating_count),
-        SearchRange::RangeTo(end) => (..*end).contains(&subject.rating_count),
-        SearchRange::RangeFrom(begin) => (*begin..).contains(&subject.rating_count),
-        SearchRange::RangeFull => true,
-      } {
-        return None;
+    }) {
+      return None;
+    }
+    // date
+    if !ticket.date.contains(date_mapper(subject)) {
+      return None;
+    }
+    // rank
+    if !ticket.rank.contains(subject.rank) {
+      return None;
+    }
+    // fav
+    if !ticket.rating_count.contains(subject.rating_count) {
+      return None;
+    }
+
+    // user
+    let user_recommend = match ticket.for_user {
+      Some(user_id) => {
+        if let Some(fav_mode) = ticket.fav_mode {
+          let is_fav = self.is_user_fav(user_id, subject.subject_id);
+          if is_fav^fav_mode {
+            return None;
+          }
+        }
+        if !ticket.collection_list.is_empty() && !ticket.collection_matches(self.get_user_collection_state(user_id, subject.subject_id)) {
+          return None;
+        }
+        match self.get_user_subject_relation(user_id, subject.subject_id) {
+          Some(relation) => relation as f32,
+          None => { return None; }
+        }
       }
+      None => { score_mapper(subject) }
+    };
 
-      // user
-      let user_recommend = match ticket.for_user {
-        Some(user_id) => {
-          if let Some(fav_mode) = ticket.fav_mode {
-            let is_fav = self.is_user_fav(user_id, subject.subject_id);
-            if is_fav^fav_mode {
+    // keyword
+    let mut term_list = Vec::with_capacity(ticket.keyword_list.len());
+    if !ticket.keyword_list.is_empty() {
+      let name = subject.name.to_lowercase();
+      let name_cn = subject.name_cn.to_lowercase();
+      let name_cache = name.chars().collect::<Vec<_>>();
+      let name_cn_cache = name_cn.chars().collect::<Vec<_>>();
+      let field_list = [
+        (&name_cn, &name_cn_cache, NAME_CN_BOOST, self.avg_title_len.0),
+        (&name, &name_cache, NAME_BOOST, self.avg_title_len.1),
+      ];
+      for (kwd, cached_kwd) in ticket.keyword_list.iter().zip(cached_ticket_keyword.iter()) {
+        let x = match kwd {
+          SearchMode::ExactMatch(r) | SearchMode::PartialMatch(r) => match r {
+            Relation::Include(x) | Relation::Exclude(x) => x.as_str(),
+          },
+        };
+        let hit = field_list.iter().any(|(field, _, _, _)| match_keyword_exact(x, field));
+        let term_score = || field_list.iter().map(|(field, cached_field, boost, avg_len)| {
+          boost * bm25_term(soft_term_frequency(x, cached_kwd, field, cached_field), cached_field.len(), *avg_len)
+        }).sum::<f32>();
+        match kwd {
+          SearchMode::ExactMatch(Relation::Include(_)) => {
+            if !hit {
               return None;
             }
-          }
-          match self.get_user_subject_relation(user_id, subject.subject_id) {
-            Some(relation) => relation as f32,
-            None => { return None; }
-          }
+            term_list.push((term_score(), true));
+          },
+          SearchMode::ExactMatch(Relation::Exclude(_)) => {
+            if hit {
+              return None;
+            }
+            term_list.push((0.0, false));
+          },
+          SearchMode::PartialMatch(Relation::Include(_)) => {
+            term_list.push((term_score(), hit));
+          },
+          SearchMode::PartialMatch(Relation::Exclude(_)) => {
+            if hit {
+              return None;
+            }
+            term_list.push((-term_score(), false));
+          },
         }
-        None => { score_mapper(subject) }
-      };
+      }
+    }
 
-      // keyword
-      let mut keyword_relative = 0.0;
-      if !ticket.keyword_list.is_empty() {
-        let name = subject.name.to_lowercase();
-        let name_cn = subject.name_cn.to_lowercase();
-        let name_cache = name.chars().collect::<Vec<_>>();
-        let name_cn_cache = name_cn.chars().collect::<Vec<_>>();
-        for (kwd, cached_kwd) in ticket.keyword_list.iter().zip(cached_ticket_keyword.iter()) {
-          match kwd {
-            SearchMode::ExactMatch(kwd_relation) => {
-              match kwd_relation {
-                Relation::Include(x) => {
-                  if !(match_keyword_exact(x.as_str(), name.as_str()) || match_keyword_exact(x.as_str(), name.as_str())) {
-                    return None;
-                  }
-                }
-                Relation::Exclude(x) => {
-                  if match_keyword_exact(x.as_str(), name_cn.as_str()) || match_keyword_exact(x.as_str(), name.as_str()) {
-                    return None;
-                  }
-                }
-              }
-            },
-            SearchMode::PartialMatch(kwd_relation) => {
-              match kwd_relation {
-                Relation::Include(x) => {
-                  if match_keyword_exact(x.as_str(), name_cn.as_str()) || match_keyword_exact(x.as_str(), name.as_str()) {
-                    keyword_relative += 1.0;
-                  }
-                  keyword_relative += match_keyword_partial(cached_kwd, &name_cn_cache).max(match_keyword_partial(cached_kwd, &name_cache));
-                }
-                Relation::Exclude(x) => {
-                  if match_keyword_exact(x.as_str(), name_cn.as_str()) || match_keyword_exact(x.as_str(), name.as_str()) {
-                    keyword_relative -= 1.0;
-                  }
-                  keyword_relative -= match_keyword_partial(cached_kwd, &name_cn_cache).max(match_keyword_partial(cached_kwd, &name_cache));
-                }
-              }
-              if keyword_relative <= 0.0 {
-                return None;
-              }
-            },
-          }
-        }
-        keyword_relative /= ticket.keyword_list.len() as f32;
+    Some((SearchResult {
+      subject,
+      keyword_relative: 0.0,
+      user_recommend,
+    }, term_list))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn subject(subject_id: u32, score: f32) -> PackedSubject {
+    PackedSubject {
+      subject_id,
+      rank: 0,
+      name: format!("subject {}", subject_id),
+      name_cn: String::new(),
+      image_partial_url: String::new(),
+      tag_list: Vec::new(),
+      score,
+      rating_count: 100,
+      air_y: 2000,
+      air_m: 1,
+      air_d: 1,
+      sub_type: PackedSubjectSubtype::TV,
+      is_r18: false,
+    }
+  }
+
+  #[test]
+  fn sort_with_nan_score() {
+    let subject_list = [subject(1, 7.0), subject(2, f32::NAN), subject(3, 8.0), subject(4, 7.0)];
+    for ascent in [false, true].iter() {
+      let key_list = [SortKey { mode: SortMode::Rank, ascent: *ascent }];
+      let l = top_k_result_unsearched(subject_list.iter().collect(), &key_list, subject_list.len());
+      let id_list = l.iter().map(|x| x.subject_id).collect::<Vec<_>>();
+      assert_eq!(id_list, if *ascent { vec![1, 4, 3, 2] } else { vec![3, 1, 4, 2] });
+    }
+
+    let result_list = subject_list.iter().map(|x| SearchResult { subject: x, keyword_relative: x.score, user_recommend: f32::NAN }).collect::<Vec<_>>();
+    let key_list = [SortKey { mode: SortMode::Relative, ascent: false }, SortKey { mode: SortMode::Recommend, ascent: true }];
+    let l = top_k_result(result_list, &key_list, 2);
+    assert_eq!(l.iter().map(|x| x.subject.subject_id).collect::<Vec<_>>(), vec![3, 1]);
+  }
+
+  // Plain O(nm) DP with a free start and end in `text`
+  fn naive_substring_distance(pattern: &[char], text: &[char]) -> usize {
+    let mut dp = vec![vec![0; text.len() + 1]; pattern.len() + 1];
+    for i in 1..=pattern.len() {
+      dp[i][0] = i;
+      for j in 1..=text.len() {
+        dp[i][j] = (dp[i - 1][j - 1] + (pattern[i - 1] != text[j - 1]) as usize).min(dp[i - 1][j] + 1).min(dp[i][j - 1] + 1);
       }
+    }
+    dp[pattern.len()].iter().cloned().min().unwrap()
+  }
 
-      Some(SearchResult {
-        subject,
-        keyword_relative,
-        user_recommend,
-      })
-    }).collect()
+  fn check_substring_distance(pattern: &[char], text: &[char]) {
+    let expected = naive_substring_distance(pattern, text);
+    if pattern.len() <= 64 {
+      assert_eq!(myers_substring_distance(pattern, text), expected, "{:?} in {:?}", pattern, text);
+    }
+    for max_distance in 0..=pattern.len() + 1 {
+      let row = row_substring_distance(pattern, text, max_distance);
+      assert_eq!(row.min(max_distance + 1), expected.min(max_distance + 1), "{:?} in {:?} within {}", pattern, text, max_distance);
+      let d = substring_edit_distance(pattern, text, max_distance);
+      assert_eq!(d, if expected <= max_distance { Some(expected) } else { None }, "{:?} in {:?} within {}", pattern, text, max_distance);
+    }
+  }
+
+  #[test]
+  fn substring_distance_matches_naive_dp() {
+    let chars = |s: &str| s.chars().collect::<Vec<_>>();
+    check_substring_distance(&[], &[]);
+    check_substring_distance(&[], &chars("abc"));
+    check_substring_distance(&chars("abc"), &[]);
+    check_substring_distance(&chars("abcdef"), &chars("abd"));
+    check_substring_distance(&chars("魔法少女"), &chars("魔法少女小圆"));
+    check_substring_distance(&chars("xyz"), &chars("aaaa"));
+    assert_eq!(substring_edit_distance(&chars("abcdef"), &chars("ab"), 3), None);
+    assert_eq!(substring_edit_distance(&chars("abcd"), &chars("xxabxdxx"), 0), None);
+    assert_eq!(substring_edit_distance(&chars("abcd"), &chars("xxabxdxx"), 1), Some(1));
+
+    // xorshift, so the inputs stay the same from run to run
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = |n: usize| {
+      state ^= state << 13;
+      state ^= state >> 7;
+      state ^= state << 17;
+      (state % n as u64) as usize
+    };
+    let alphabet = chars("abc魔法");
+    for _ in 0..2000 {
+      let (m, n) = (next(12), next(16));
+      let pattern = (0..m).map(|_| alphabet[next(alphabet.len())]).collect::<Vec<_>>();
+      let text = (0..n).map(|_| alphabet[next(alphabet.len())]).collect::<Vec<_>>();
+      check_substring_distance(&pattern, &text);
+    }
+    // 64 chars is the last length Myers handles, longer ones take the fallback
+    for &m in [63, 64, 65, 80].iter() {
+      for _ in 0..20 {
+        let n = next(120);
+        let pattern = (0..m).map(|_| alphabet[next(2)]).collect::<Vec<_>>();
+        let mut text = (0..n).map(|_| alphabet[next(2)]).collect::<Vec<_>>();
+        if n > m && next(2) == 0 {
+          let at = next(n - m);
+          text[at..at + m].copy_from_slice(&pattern);
+          text[at + next(m)] = alphabet[2];
+        }
+        check_substring_distance(&pattern, &text);
+      }
+    }
   }
-}
\ No newline at end of file
+}
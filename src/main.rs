@@ -13,35 +13,76 @@ extern crate serde_json;
 extern crate chrono;
 use chrono::prelude::*;
 extern crate percent_encoding;
-use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, PATH_SEGMENT_ENCODE_SET};
+extern crate mime;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 mod db;
+mod cache;
+mod bitset;
+mod config;
+
+type QueryCacheKey = (db::SearchTicket, Vec<db::SortKey>, u64);
+type QueryCacheValue = Arc<QueryCacheEntry>;
+
+// The first items of a sorted search result along with the facet counts over all of it, `result_list` holds every item when shorter than `result_count`
+struct QueryCacheEntry {
+  result_count: usize,
+  result_list: Vec<db::SearchResult<'static>>,
+  facet: db::Facet,
+}
 
 lazy_static! {
+  static ref CONFIG: config::Config = config::Config::load();
   static ref TERA: Tera = {
-    let mut tera = compile_templates!("template/**/*");
+    let mut tera = compile_templates!(CONFIG.template_glob().as_str());
     tera.autoescape_on(vec!["html", ".sql"]);
     tera
   };
 
-  static ref DB: db::DB<'static> = db::DB::open("packed.db");
+  static ref DB: db::DB<'static> = db::DB::open(&CONFIG.db_path);
   static ref S_D_FAC: String = format!("{:?}", DB.d_fac());
   static ref S_DB_DATE: String = {
     let (m, d, y) = DB.db_date();
     format!("{:02}/{:02}/{:04}", m, d, y)
   };
+  static ref CATALOGUE_FACET: db::Facet = DB.facet_count(DB.subject_iter());
+  static ref YEAR_BUCKET_LIST: Vec<db::SearchRange> = year_bucket_list(&CATALOGUE_FACET, YEAR_BUCKET_COUNT);
+  static ref QUERY_CACHE: cache::LruCache<QueryCacheKey, QueryCacheValue> = cache::LruCache::new(CONFIG.query_cache_capacity, Duration::from_secs(CONFIG.query_cache_ttl));
 }
 
-const REV: u32 = 1;
+const REV: u32 = 3;
+const TAG_DATALIST_SIZE: usize = 200;
+const TAG_SUGGEST_SIZE: usize = 10;
+const TAG_FACET_SIZE: usize = 50;
+const SUBJECT_SUGGEST_SIZE: usize = 10;
+const USERNAME_SUGGEST_SIZE: usize = 3;
+const SIMILAR_SUBJECT_COUNT: usize = 10;
+const USER_RECOMMEND_SIZE: usize = 10;
+const USER_FAV_SIZE: usize = 10;
+const USER_TAG_PROFILE_SIZE: usize = 20;
+const COMPARE_LIST_SIZE: usize = 10;
+const SIMILAR_USER_SIZE: usize = 10;
+const SIMILAR_USER_FAV_SIZE: usize = 5;
+const YEAR_BUCKET_COUNT: usize = 5;
+const QUERY_STR_MAX_LEN: usize = 1024; // UTF-8 bytes of the decoded JSON, static/main.js checks the same
+const COVER_PLACEHOLDER: &str = "cover_placeholder.svg";
+const COVER_MAX_AGE: u32 = 30 * 24 * 3600;
+const QUERY_CACHE_PREFETCH_PAGES: usize = 4;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PresentationSubject {
   link_target: String,
   image_url: String,
   title_main: String,
+  title_main_seg: Vec<(String, bool)>,
   title_orig: Option<String>,
+  title_orig_seg: Option<Vec<(String, bool)>>,
   sub_type: &'static str,
   info: String,
   rank: u32,
@@ -61,15 +102,201 @@ struct PresentationPager {
   max_page: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationTagCount {
+  name: String,
+  count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationUnknownTag {
+  name: String,
+  suggestion_list: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationSubjectSuggestion {
+  subject_id: u32,
+  title_main: String,
+  title_orig: Option<String>,
+  image_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationSubjectTag {
+  name: String,
+  weight: String,
+  search_link: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationSubjectDetail {
+  subject_id: u32,
+  external_link: String,
+  image_url: String,
+  title_main: String,
+  title_orig: Option<String>,
+  sub_type: &'static str,
+  info: String,
+  rank: u32,
+  star_count: String,
+  rating_count: u32,
+  is_r18: bool,
+  tag_list: Vec<PresentationSubjectTag>,
+  similar_list: Vec<(PresentationSubject, String)>,
+  user: String,
+  known_user: Option<(u32, String)>,
+  recommend_rate: Option<String>,
+  is_fav: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationUser {
+  user_id: u32,
+  name: String,
+  fav_count: usize,
+  tag_profile: Vec<PresentationSubjectTag>,
+  recommend_list: Vec<PresentationSubject>,
+  fav_list: Vec<PresentationSubject>,
+  recommend_link: String,
+  fav_link: String,
+  similar_link: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationComparison {
+  user_a: (u32, String),
+  user_b: (u32, String),
+  fav_count: (usize, usize),
+  fav_jaccard: f32,
+  relation_correlation: f32,
+  shared_fav_count: usize,
+  shared_fav_list: Vec<PresentationSubject>,
+  a_from_b_list: Vec<PresentationSubject>,
+  b_from_a_list: Vec<PresentationSubject>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationNeighbour {
+  user: (u32, String),
+  score: f32,
+  fav_count: usize,
+  fav_jaccard: f32,
+  relation_correlation: f32,
+  shared_fav_count: usize,
+  fav_list: Vec<PresentationSubject>, // their favourites the user has not favourited, by the user's rank
+  compare_link: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationSimilarUser {
+  user: (u32, String),
+  neighbour_list: Vec<PresentationNeighbour>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct SubjectQuery {
+  user: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct SuggestQuery {
+  q: String,
+  n: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationStat {
+  query_cache: cache::CacheStat,
+}
+
+// `[include, name, min_weight?, top_n?]`
+#[derive(Deserialize, Debug, Clone)]
+struct QueryTag(u8, String, #[serde(default)] Option<f32>, #[serde(default)] Option<u8>);
+
+// query_str is a JSON array, trailing items are optional so that old links keep working
+#[derive(Deserialize, Debug, Clone)]
+struct SearchQuery {
+  kwd_list: Vec<(u8, String)>,
+  tag_list: Vec<QueryTag>,
+  year_list: Vec<(Option<u16>, Option<u16>)>,
+  user: Option<String>,
+  r18: u8,
+  fav: u8,
+  #[serde(default)]
+  sub_type_list: Vec<u8>,
+  #[serde(default)]
+  season_list: Vec<u8>,
+  #[serde(default)]
+  date: (Option<u32>, Option<u32>), // inclusive `yyyymmdd`
+  #[serde(default)]
+  collection_list: Vec<(u8, u8)>, // `[include, state]`, state as bangumi's collection type
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationTag {
+  name: String,
+  state: u8,
+  min_weight: Option<f32>,
+  top_n: Option<u8>,
+  count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationYear {
+  label: String,
+  from: Option<u16>,
+  to: Option<u16>,
+  selected: bool,
+  count: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationFacet {
+  tag: Vec<PresentationTagCount>,
+  year: BTreeMap<u16, u32>,
+  season: BTreeMap<String, u32>,
+  sub_type: BTreeMap<String, u32>,
+  r18: u32,
+  non_r18: u32,
+}
+
+// Why a search came back empty, `kind` is one of `unknown_user`, `excluded_user` or `no_match`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PresentationSearchError {
+  kind: &'static str,
+  user: Option<String>,
+  suggestion_list: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PresentationSearchResult {
+  result_count: usize,
+  error: Option<PresentationSearchError>,
+  username: Option<String>,
+  subject_list: Vec<PresentationSubject>,
+  facet: PresentationFacet,
+  unknown_tag_list: Vec<PresentationUnknownTag>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct PresentationSearch {
   kwd_str: String,
   user: String,
-  year: (bool, bool, bool, bool, bool, bool),
-  tag: (u8, u8, u8, u8, u8, u8, u8, u8, u8, u8,),
-  ord: u8,
+  known_user: Option<(u32, String)>,
+  year: Vec<PresentationYear>,
+  season: Vec<(u8, String, bool, u32)>,
+  date: (String, String),
+  current_season: (String, String),
+  sub_type: Vec<(u8, String, bool, u32)>,
+  tag: Vec<PresentationTag>,
+  tag_datalist: Vec<String>,
+  ord: Option<u8>, // single key sort modes only, for the legacy sort bar
+  sort_mode: String,
   r18: u8,
+  r18_count: (u32, u32),
   fav: u8,
+  collection: Vec<(u8, String, u8)>, // state 1 for included, 0 for excluded, 2 for neither
   base_url: String,
   curr_skip: u32,
 }
@@ -93,8 +320,8 @@ where
   PresentationPager { max_link, min_link, prev_link, next_link, page_list, curr_page: curr_page + 1, max_page }
 }
 
-fn parse_sort_mode_str(s: &str) -> Result<(bool, db::SortMode), actix_web::HttpResponse> {
-  Ok(match s {
+fn parse_sort_key_str(s: &str) -> Option<db::SortKey> {
+  let (ascent, mode) = match s {
     "ar" => (true, db::SortMode::Recommend),
     "dr" => (false, db::SortMode::Recommend),
     "an" => (true, db::SortMode::Name),
@@ -107,12 +334,21 @@ fn parse_sort_mode_str(s: &str) -> Result<(bool, db::SortMode), actix_web::HttpR
     "df" => (false, db::SortMode::FavCount),
     "al" => (true, db::SortMode::Relative),
     "dl" => (false, db::SortMode::Relative),
-    _ => { return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad sort_mode_str")); },
-  })
+    _ => { return None; },
+  };
+  Some(db::SortKey { mode, ascent })
 }
 
-fn encode_sort_mode_str_to_u8(s: &str) -> u8 {
-  ["ar", "dr", "al", "dl", "ak", "dk", "ad", "dd", "af", "df"].iter().enumerate().find(|(_, x)| *x == &s).unwrap().0 as u8
+// sort_mode_str is a comma separated list of sort keys, e.g. `dd,dk`
+fn parse_sort_mode_str(s: &str) -> Result<Vec<db::SortKey>, actix_web::HttpResponse> {
+  match s.split(',').map(parse_sort_key_str).collect::<Option<Vec<_>>>() {
+    Some(l) if l.len() <= 4 => Ok(l),
+    _ => Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad sort_mode_str")),
+  }
+}
+
+fn encode_sort_mode_str_to_u8(s: &str) -> Option<u8> {
+  ["ar", "dr", "al", "dl", "ak", "dk", "ad", "dd", "af", "df"].iter().position(|x| *x == s).map(|x| x as u8)
 }
 
 fn encode_sub_type_to_str(t: db::PackedSubjectSubtype) -> &'static str {
@@ -125,12 +361,271 @@ fn encode_sub_type_to_str(t: db::PackedSubjectSubtype) -> &'static str {
   }
 }
 
-fn subject_to_presentation(x: &db::PackedSubject, user_recommend: Option<usize>) -> PresentationSubject {
+fn decode_sub_type(x: u8) -> Option<db::PackedSubjectSubtype> {
+  match x {
+    0 => Some(db::PackedSubjectSubtype::Unknown),
+    1 => Some(db::PackedSubjectSubtype::TV),
+    2 => Some(db::PackedSubjectSubtype::OVA),
+    3 => Some(db::PackedSubjectSubtype::Web),
+    4 => Some(db::PackedSubjectSubtype::Movie),
+    _ => None,
+  }
+}
+
+fn sub_type_to_presentation(sub_type_list: &[db::PackedSubjectSubtype], facet: &db::Facet) -> Vec<(u8, String, bool, u32)> {
+  (1..5).map(|i| {
+    let t = decode_sub_type(i).unwrap();
+    (i, encode_sub_type_to_str(t).to_string(), sub_type_list.contains(&t), facet.sub_type_count_list[t as usize])
+  }).collect()
+}
+
+fn decode_season(x: u8) -> Option<db::Season> {
+  match x {
+    1 => Some(db::Season::Winter),
+    2 => Some(db::Season::Spring),
+    3 => Some(db::Season::Summer),
+    4 => Some(db::Season::Autumn),
+    _ => None,
+  }
+}
+
+fn encode_season_to_str(x: db::Season) -> &'static str {
+  match x {
+    db::Season::Winter => "冬",
+    db::Season::Spring => "春",
+    db::Season::Summer => "夏",
+    db::Season::Autumn => "秋",
+  }
+}
+
+fn season_to_presentation(season_list: &[db::Season], facet: &db::Facet) -> Vec<(u8, String, bool, u32)> {
+  (1..5).map(|i| {
+    let x = decode_season(i).unwrap();
+    (i, format!("{} ({}月)", encode_season_to_str(x), x.first_month()), season_list.contains(&x), facet.season_count_list[x as usize])
+  }).collect()
+}
+
+fn decode_collection_state(x: u8) -> Option<db::CollectionState> {
+  match x {
+    1 => Some(db::CollectionState::Wish),
+    2 => Some(db::CollectionState::Watched),
+    3 => Some(db::CollectionState::Watching),
+    4 => Some(db::CollectionState::OnHold),
+    5 => Some(db::CollectionState::Dropped),
+    _ => None,
+  }
+}
+
+fn encode_collection_state_to_str(x: db::CollectionState) -> &'static str {
+  match x {
+    db::CollectionState::Wish => "想看",
+    db::CollectionState::Watched => "看过",
+    db::CollectionState::Watching => "在看",
+    db::CollectionState::OnHold => "搁置",
+    db::CollectionState::Dropped => "抛弃",
+  }
+}
+
+fn collection_to_presentation(collection_list: &[db::Relation<db::CollectionState>]) -> Vec<(u8, String, u8)> {
+  (1..6).map(|i| {
+    let x = decode_collection_state(i).unwrap();
+    let state = if collection_list.contains(&db::Relation::Include(x)) { 1 } else if collection_list.contains(&db::Relation::Exclude(x)) { 0 } else { 2 };
+    (i, encode_collection_state_to_str(x).to_string(), state)
+  }).collect()
+}
+
+// Year ranges holding about the same number of subjects each
+fn year_bucket_list(facet: &db::Facet, n_bucket: usize) -> Vec<db::SearchRange> {
+  let year_count_list = facet.year_count_map.iter().filter(|(year, _)| **year > 0).collect::<Vec<_>>();
+  let total = year_count_list.iter().map(|(_, count)| **count as usize).sum::<usize>();
+  let mut boundary_list = Vec::new();
+  let mut acc = 0;
+  for (year, count) in year_count_list.iter().take(year_count_list.len().saturating_sub(1)) {
+    acc += **count as usize;
+    if boundary_list.len() + 1 < n_bucket && acc * n_bucket >= total * (boundary_list.len() + 1) {
+      boundary_list.push(**year as u32 + 1);
+    }
+  }
+  if boundary_list.is_empty() {
+    return vec![db::SearchRange::RangeFull];
+  }
+  let mut bucket_list = vec![db::SearchRange::RangeTo(boundary_list[0])];
+  bucket_list.extend(boundary_list.windows(2).map(|x| db::SearchRange::Range(x[0], x[1])));
+  bucket_list.push(db::SearchRange::RangeFrom(*boundary_list.last().unwrap()));
+  bucket_list
+}
+
+// Buckets first, then ranges from older links that no longer line up with any bucket, so they stay visible and removable
+fn year_to_presentation(year_list: &[db::SearchRange], facet: &db::Facet) -> Vec<PresentationYear> {
+  let mut extra_list: Vec<&db::SearchRange> = Vec::new();
+  for x in year_list.iter().filter(|x| !YEAR_BUCKET_LIST.contains(x)) {
+    if !extra_list.contains(&x) {
+      extra_list.push(x);
+    }
+  }
+  YEAR_BUCKET_LIST.iter().chain(extra_list).map(|x| {
+    let (from, to) = match x {
+      db::SearchRange::Range(a, b) => (Some(*a as u16), Some(*b as u16)),
+      db::SearchRange::RangeTo(b) => (None, Some(*b as u16)),
+      db::SearchRange::RangeFrom(a) => (Some(*a as u16), None),
+      db::SearchRange::RangeFull => (None, None),
+    };
+    PresentationYear {
+      label: format!("({}..{})", from.map_or(String::new(), |x| x.to_string()), to.map_or(String::new(), |x| x.to_string())),
+      from,
+      to,
+      selected: year_list.contains(x),
+      count: facet.year_range_count(x),
+    }
+  }).collect()
+}
+
+// First and last day of the season airing now, in JST
+fn current_season_date_range() -> (NaiveDate, NaiveDate) {
+  let today = Utc::now().with_timezone(&FixedOffset::east_opt(9 * 3600).unwrap()).date_naive();
+  let season = db::Season::from_month(today.month() as u8).unwrap();
+  let first = NaiveDate::from_ymd_opt(today.year(), season.first_month() as u32, 1).unwrap();
+  let next = match season {
+    db::Season::Autumn => NaiveDate::from_ymd_opt(today.year() + 1, 1, 1),
+    _ => NaiveDate::from_ymd_opt(today.year(), season.first_month() as u32 + 3, 1),
+  }.unwrap();
+  (first, next.pred_opt().unwrap())
+}
+
+fn current_season_to_presentation() -> (String, String) {
+  let (first, last) = current_season_date_range();
+  (first.format("%Y-%m-%d").to_string(), last.format("%Y-%m-%d").to_string())
+}
+
+// `yyyymmdd` from query_str into `db::encode_date`, a lower bound on the 1st also takes subjects with an unknown day (or month)
+fn decode_query_date(x: u32, is_lower_bound: bool) -> Option<u32> {
+  let (y, m, d) = (x / 10000, (x / 100 % 100) as u8, (x % 100) as u8);
+  if y > u16::MAX as u32 || m > 12 || d > 31 {
+    return None;
+  }
+  Some(if !is_lower_bound {
+    db::encode_date(y as u16, m, d) + 1
+  }
+  else if d > 1 {
+    db::encode_date(y as u16, m, d)
+  }
+  else if m > 1 {
+    db::encode_date(y as u16, m, 0)
+  }
+  else {
+    db::encode_date(y as u16, 0, 0)
+  })
+}
+
+fn query_date_to_presentation(x: Option<u32>) -> String {
+  x.map_or(String::new(), |x| format!("{:04}-{:02}-{:02}", x / 10000, x / 100 % 100, x % 100))
+}
+
+fn facet_to_presentation(facet: &db::Facet) -> PresentationFacet {
+  let mut tag = facet.tag_count_list.iter().map(|(tag_id, count)| PresentationTagCount {
+    name: DB.get_tag_name_by_id(*tag_id).unwrap().to_string(),
+    count: *count,
+  }).collect::<Vec<_>>();
+  tag.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+  tag.truncate(TAG_FACET_SIZE);
+  PresentationFacet {
+    tag,
+    year: facet.year_count_map.clone(),
+    season: (1..5).map(|i| {
+      let x = decode_season(i).unwrap();
+      (format!("{:?}", x), facet.season_count_list[x as usize])
+    }).collect(),
+    sub_type: (0..5).map(|i| {
+      let t = decode_sub_type(i).unwrap();
+      (format!("{:?}", t), facet.sub_type_count_list[t as usize])
+    }).collect(),
+    r18: facet.r18_count,
+    non_r18: facet.subject_count - facet.r18_count,
+  }
+}
+
+// The most frequent tags followed by any other tag in the query
+fn tag_panel_to_presentation(s_tag_list: &[QueryTag], facet: &db::Facet) -> Vec<PresentationTag> {
+  let mut s_tag_list = s_tag_list.iter().map(|x| (DB.get_tag_id_by_name(x.1.as_str()), x)).collect::<Vec<_>>();
+  let mut tag_list = DB.frequent_tag_iter().take(CONFIG.tag_panel_size).map(|tag_id| {
+    let name = DB.get_tag_name_by_id(tag_id).unwrap().to_string();
+    match s_tag_list.iter().position(|(x, _)| *x == Some(tag_id)) {
+      Some(i) => {
+        let (_, QueryTag(state, _, min_weight, top_n)) = s_tag_list.remove(i);
+        PresentationTag { name, state: *state, min_weight: *min_weight, top_n: *top_n, count: Some(facet.tag_count(tag_id)) }
+      },
+      None => PresentationTag { name, state: 2, min_weight: None, top_n: None, count: Some(facet.tag_count(tag_id)) },
+    }
+  }).collect::<Vec<_>>();
+  tag_list.extend(s_tag_list.into_iter().map(|(tag_id, QueryTag(state, name, min_weight, top_n))| PresentationTag {
+    name: tag_id.and_then(|x| DB.get_tag_name_by_id(x)).unwrap_or(name.as_str()).to_string(),
+    state: *state,
+    min_weight: *min_weight,
+    top_n: *top_n,
+    count: tag_id.map(|x| facet.tag_count(x)),
+  }));
+  tag_list
+}
+
+fn unknown_tag_to_presentation(s_tag_list: &[QueryTag]) -> Vec<PresentationUnknownTag> {
+  s_tag_list.iter().filter(|x| DB.get_tag_id_by_name(x.1.as_str()).is_none()).map(|x| PresentationUnknownTag {
+    name: x.1.clone(),
+    suggestion_list: tag_suggestion_to_presentation(x.1.as_str(), 3).into_iter().map(|x| x.name).collect(),
+  }).collect()
+}
+
+fn tag_suggestion_to_presentation(query: &str, n: usize) -> Vec<PresentationTagCount> {
+  DB.suggest_tag(query, n).into_iter().map(|x| PresentationTagCount {
+    name: DB.get_tag_name_by_id(x).unwrap().to_string(),
+    count: DB.get_tag_count_by_id(x),
+  }).collect()
+}
+
+fn tag_datalist_to_presentation() -> Vec<String> {
+  DB.frequent_tag_iter().skip(CONFIG.tag_panel_size).take(TAG_DATALIST_SIZE).map(|x| DB.get_tag_name_by_id(x).unwrap().to_string()).collect()
+}
+
+fn cover_url(x: &db::PackedSubject) -> String {
+  CONFIG.cover_url(&x.image_partial_url)
+}
+
+// Splits `s` into (text, is_highlighted) segments by char ranges
+fn highlight_to_presentation(s: &str, span_list: &[(usize, usize)]) -> Vec<(String, bool)> {
+  let char_list = s.chars().collect::<Vec<_>>();
+  let mut seg_list = Vec::new();
+  let n = char_list.len();
+  let mut i = 0;
+  for (begin, end) in span_list.iter().map(|(a, b)| ((*a).min(n), (*b).min(n))) {
+    if begin > i {
+      seg_list.push((char_list[i..begin].iter().collect(), false));
+    }
+    if end > begin.max(i) {
+      seg_list.push((char_list[begin.max(i)..end].iter().collect(), true));
+    }
+    i = i.max(end);
+  }
+  if i < n || seg_list.is_empty() {
+    seg_list.push((char_list[i..].iter().collect(), false));
+  }
+  seg_list
+}
+
+// Detail page of `subject_id`, carrying the user along so that their rank shows up there too
+fn subject_link(subject_id: u32, user_id: Option<u32>) -> String {
+  match user_id {
+    Some(uid) => format!("/subject/{}?user={}", subject_id, uid),
+    None => format!("/subject/{}", subject_id),
+  }
+}
+
+fn subject_to_presentation(x: &db::PackedSubject, user_id: Option<u32>, user_recommend: Option<usize>, name_span_list: &[(usize, usize)], name_cn_span_list: &[(usize, usize)]) -> PresentationSubject {
   PresentationSubject {
-    link_target: format!("https://bgm.tv/subject/{}", x.subject_id),
-    image_url: format!("https://lain.bgm.tv/pic/cover/{}", &x.image_partial_url),
+    link_target: subject_link(x.subject_id, user_id),
+    image_url: cover_url(x),
     title_main: if !x.name_cn.is_empty() { x.name_cn.clone() } else { x.name.clone() },
+    title_main_seg: if !x.name_cn.is_empty() { highlight_to_presentation(&x.name_cn, name_cn_span_list) } else { highlight_to_presentation(&x.name, name_span_list) },
     title_orig: if x.name_cn.is_empty() { None } else { Some(x.name.clone()) },
+    title_orig_seg: if x.name_cn.is_empty() { None } else { Some(highlight_to_presentation(&x.name, name_span_list)) },
     sub_type: encode_sub_type_to_str(x.sub_type),
     info: format!("{:02}/{:02}/{:04}", x.air_m, x.air_d, x.air_y),
     rank: x.rank,
@@ -140,6 +635,53 @@ fn subject_to_presentation(x: &db::PackedSubject, user_recommend: Option<usize>)
   }
 }
 
+// Per-stage timings of a search, both are None when served from QUERY_CACHE
+struct QueryTiming {
+  filter: Option<Duration>,
+  sort: Option<Duration>,
+  parallel: bool,
+}
+
+impl QueryTiming {
+  fn describe(&self, total: Duration) -> String {
+    match (self.filter, self.sort) {
+      (Some(filter), Some(sort)) => format!("{}μs (filter{} {}μs, sort {}μs)", total.as_micros(), if self.parallel { "∥" } else { "" }, filter.as_micros(), sort.as_micros()),
+      _ => format!("{}μs (cached)", total.as_micros()),
+    }
+  }
+}
+
+// Sorted search result holding at least its first `k` items, a cached prefix too short for `k` is searched again
+fn perform_search(ticket: &db::SearchTicket, sort_key_list: &[db::SortKey], k: usize) -> (QueryCacheValue, QueryTiming) {
+  let mut timing = QueryTiming { filter: None, sort: None, parallel: ticket.is_expensive() };
+  let mut run = |k: usize| {
+    let start_time = Instant::now();
+    let result = DB.search_by_ticket(ticket);
+    let result_count = result.len();
+    let facet = DB.facet_count(result.iter().map(|x| x.subject));
+    timing.filter = Some(start_time.elapsed());
+    let start_time = Instant::now();
+    let result_list = db::top_k_result(result, sort_key_list, k);
+    timing.sort = Some(start_time.elapsed());
+    Arc::new(QueryCacheEntry { result_count, result_list, facet })
+  };
+  if QUERY_CACHE.capacity() == 0 {
+    let result = run(k);
+    return (result, timing);
+  }
+  let key = (ticket.canonical(), sort_key_list.to_vec(), DB.generation());
+  if let Some(result) = QUERY_CACHE.get(&key, |x| x.result_list.len() >= k.min(x.result_count)) {
+    return (result, timing);
+  }
+  // a few pages past `k` so that paging on does not search again
+  let prefetch_size = CONFIG.page_size * QUERY_CACHE_PREFETCH_PAGES;
+  let result = run((k + prefetch_size - 1) / prefetch_size * prefetch_size);
+  // the facet is charged per distinct tag and year, an empty result still takes a slot
+  let weight = result.result_list.len() + result.facet.tag_count_list.len() + result.facet.year_count_map.len() + 1;
+  QUERY_CACHE.insert(key, result.clone(), weight);
+  (result, timing)
+}
+
 /* unsearched */
 fn unsearched(info: actix_web::Path<(String, u32,)>) -> impl Responder {
   let code = 200;
@@ -152,30 +694,41 @@ fn unsearched(info: actix_web::Path<(String, u32,)>) -> impl Responder {
   }
 
   /* parse sort mode */
-  let (is_sort_ascent, sort_mode) = match parse_sort_mode_str(sort_mode_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
+  let sort_key_list = match parse_sort_mode_str(sort_mode_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
 
   /* perform query */
-  let result = db::sort_result_unsearched(DB.subject_iter().collect(), sort_mode, is_sort_ascent);
+  let result = DB.sorted_subject_page(&sort_key_list, n_skip as usize, CONFIG.page_size);
 
   /* PresentationSearch */
   let search_obj = PresentationSearch {
     kwd_str: String::new(),
     user: String::new(),
-    year: (false, false, false, false, false, false,),
-    tag: (2, 2, 2, 2, 2, 2, 2, 2, 2, 2,),
+    known_user: None,
+    year: year_to_presentation(&[], &CATALOGUE_FACET),
+    season: season_to_presentation(&[], &CATALOGUE_FACET),
+    date: (String::new(), String::new()),
+    current_season: current_season_to_presentation(),
+    sub_type: sub_type_to_presentation(&[], &CATALOGUE_FACET),
+    tag: tag_panel_to_presentation(&[], &CATALOGUE_FACET),
+    tag_datalist: tag_datalist_to_presentation(),
     ord: encode_sort_mode_str_to_u8(&sort_mode_str),
+    sort_mode: sort_mode_str.clone(),
     r18: 3,
+    r18_count: (CATALOGUE_FACET.r18_count, CATALOGUE_FACET.subject_count - CATALOGUE_FACET.r18_count),
     fav: 1,
+    collection: collection_to_presentation(&[]),
     base_url: String::new(),
     curr_skip: n_skip,
   };
 
   let mut context = Context::new();
-  let result = result.into_iter().skip(n_skip as usize).take(25).map(|x| subject_to_presentation(x, None)).collect::<Vec<_>>();
+  let result = result.into_iter().map(|x| subject_to_presentation(x, None, None, &[], &[])).collect::<Vec<_>>();
   context.insert("subject_list", &result);
   context.insert("d_fac", S_D_FAC.as_str());
   context.insert("rev", &REV);
-  context.insert("pager", &construct_pager(n_skip / 25, ((result_count + 24) / 25) as u32, move |x| format!("/{}/{}", sort_mode_str, x * 25)));
+  context.insert("page_size", &CONFIG.page_size);
+  let page_size = CONFIG.page_size as u32;
+  context.insert("pager", &construct_pager(n_skip / page_size, ((result_count + CONFIG.page_size - 1) / CONFIG.page_size) as u32, move |x| format!("/{}/{}", sort_mode_str, x * page_size)));
   context.insert("search", &search_obj);
   context.insert("db_date", S_DB_DATE.as_str());
   context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
@@ -188,14 +741,145 @@ fn unsearched(info: actix_web::Path<(String, u32,)>) -> impl Responder {
   }.content_type("text/html").body(TERA.render("hako_list_tiny.html", &context).unwrap())
 }
 
+// A UID or username from a query resolved against the DB
+enum UserLookup {
+  Found(u32),
+  Excluded(String), // left out of the model by the packer for too few favourites
+  Unknown(String),
+}
+
+fn lookup_user(u: &str) -> UserLookup {
+  let u = u.trim();
+  let user_id = match u.parse::<u32>() {
+    Ok(uid) => DB.get_user_username_by_id(uid).map(|_| uid),
+    Err(_) => DB.get_user_id_by_username(u),
+  };
+  match user_id {
+    Some(uid) => UserLookup::Found(uid),
+    None => match DB.get_excluded_user(u.parse::<u32>().ok(), u) {
+      Some((_, username)) => UserLookup::Excluded(username.to_string()),
+      None => UserLookup::Unknown(u.to_string()),
+    },
+  }
+}
+
+impl UserLookup {
+  // UID 0 for a user outside the model, which matches nothing
+  fn user_id(&self) -> u32 {
+    match self {
+      UserLookup::Found(uid) => *uid,
+      _ => 0,
+    }
+  }
+
+  fn to_error(&self) -> Option<PresentationSearchError> {
+    match self {
+      UserLookup::Found(_) => None,
+      UserLookup::Excluded(username) => Some(PresentationSearchError { kind: "excluded_user", user: Some(username.clone()), suggestion_list: Vec::new() }),
+      UserLookup::Unknown(u) => Some(PresentationSearchError {
+        kind: "unknown_user",
+        user: Some(u.clone()),
+        suggestion_list: if u.parse::<u32>().is_ok() { Vec::new() } else { DB.suggest_username(u, USERNAME_SUGGEST_SIZE).into_iter().map(|x| x.to_string()).collect() },
+      }),
+    }
+  }
+}
+
+// UID and username of a user in the DB
+fn known_user_to_presentation(user_id: u32) -> Option<(u32, String)> {
+  DB.get_user_username_by_id(user_id).map(|x| (user_id, x.to_string()))
+}
+
+// Parses query_str into the query it was built from, the ticket it describes and the error for a user outside the model
+fn parse_query_str(query_str: &str) -> Result<(SearchQuery, db::SearchTicket, Option<PresentationSearchError>), actix_web::HttpResponse> {
+  if query_str.len() > QUERY_STR_MAX_LEN {
+    return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("query_str is too long"));
+  }
+  let query = match serde_json::from_str::<SearchQuery>(query_str) {
+    Ok(x) => x,
+    Err(e) => { return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body(format!("Bad query_str: {:?}", e))); }
+  };
+  let user_lookup = query.user.as_ref().map(|u| lookup_user(u));
+  let user_id = user_lookup.as_ref().map(|x| x.user_id());
+
+  let keyword_list = query.kwd_list.iter().map(|(opt, kwd)| {
+    let a = if *opt & 0b01 == 0 { db::Relation::Include(kwd.to_lowercase().clone()) } else { db::Relation::Exclude(kwd.to_lowercase().clone()) };
+    if *opt & 0b10 == 0 { db::SearchMode::PartialMatch(a) } else { db::SearchMode::ExactMatch(a) }
+  }).collect::<Vec<_>>();
+  let tag_list = query.tag_list.iter().map(|QueryTag(include, tag, min_weight, top_n)| {
+    let i = DB.get_tag_id_by_name(tag.as_str()).unwrap_or(u32::max_value());
+    let filter = db::TagFilter { tag_id: i, min_weight: *min_weight, top_n: *top_n };
+    if *include == 1 { db::Relation::Include(filter) } else { db::Relation::Exclude(filter) }
+  }).collect::<Vec<_>>();
+  let year_list = query.year_list.iter().map(|(from, to)| match from {
+    Some(a) => {
+      match to {
+        Some(b) => db::SearchRange::Range(*a as u32, *b as u32),
+        None => db::SearchRange::RangeFrom(*a as u32),
+      }
+    },
+    None => {
+      match to {
+        Some(b) => db::SearchRange::RangeTo(*b as u32),
+        None => db::SearchRange::RangeFull,
+      }
+    }
+  }).collect::<Vec<_>>();
+
+  let r18_mode = match query.r18 {
+    0b01 => Some(false),
+    0b10 => Some(true),
+    0b11 => None,
+    _ => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad r18_mode")),
+  };
+  let fav_mode = match query.fav {
+    0b01 => Some(false),
+    0b10 => Some(true),
+    0b11 => None,
+    _ => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad fav_mode")),
+  };
+  let sub_type_list = match query.sub_type_list.iter().map(|x| decode_sub_type(*x)).collect::<Option<Vec<_>>>() {
+    Some(x) => x,
+    None => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad sub_type")),
+  };
+  let season_list = match query.season_list.iter().map(|x| decode_season(*x)).collect::<Option<Vec<_>>>() {
+    Some(x) => x,
+    None => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad season")),
+  };
+  let collection_list = match query.collection_list.iter().map(|(include, x)| decode_collection_state(*x).map(|x| if *include == 1 { db::Relation::Include(x) } else { db::Relation::Exclude(x) })).collect::<Option<Vec<_>>>() {
+    Some(x) => x,
+    None => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad collection_state")),
+  };
+  let date = match (query.date.0.map(|x| decode_query_date(x, true)), query.date.1.map(|x| decode_query_date(x, false))) {
+    (Some(None), _) | (_, Some(None)) => return Err(actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad date")),
+    (Some(Some(a)), Some(Some(b))) => db::SearchRange::Range(a, b),
+    (Some(Some(a)), None) => db::SearchRange::RangeFrom(a),
+    (None, Some(Some(b))) => db::SearchRange::RangeTo(b),
+    (None, None) => db::SearchRange::RangeFull,
+  };
+
+  let ticket = db::SearchTicket {
+    keyword_list,
+    tag_list,
+    year_list,
+    season_list,
+    date,
+    rank: db::SearchRange::RangeFull,
+    rating_count: db::SearchRange::RangeFull,
+    r18: r18_mode,
+    sub_type_list,
+    for_user: user_id,
+    fav_mode: fav_mode,
+    collection_list,
+  };
+  Ok((query, ticket, user_lookup.and_then(|x| x.to_error())))
+}
+
 /* searched */
 fn searched(info: actix_web::Path<(String, String, u32,)>) -> impl Responder {
   let mut code = 200;
   let start_time = Instant::now();
   let (query_str, sort_mode_str, n_skip,) = info.into_inner();
-  if query_str.len() > 127 {
-    return actix_web::HttpResponse::BadRequest().content_type("text/plain").body("query_str is too long");
-  }
 
   let total_subject_count = DB.subject_count();
   if n_skip as usize >= total_subject_count {
@@ -203,123 +887,62 @@ fn searched(info: actix_web::Path<(String, String, u32,)>) -> impl Responder {
   }
 
   /* parse sort mode */
-  let (is_sort_ascent, sort_mode) = match parse_sort_mode_str(sort_mode_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
+  let sort_key_list = match parse_sort_mode_str(sort_mode_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
 
   /* parse search ticket */
-  let (s_kwd_list, s_tag_list, s_year_list, s_user, s_r18, s_fav) = match serde_json::from_str::<(Vec<(u8, String)>, Vec<(u8, String)>, Vec<(Option<u16>, Option<u16>)>, Option<String>, u8, u8)>(query_str.as_str()) {
-    Ok(x) => x,
-    Err(e) => { return actix_web::HttpResponse::BadRequest().content_type("text/plain").body(format!("Bad query_str: {:?}", e));  }
-  };
-  let user_id = s_user.as_ref().map(|u| match u.parse::<u32>() {
-    Ok(uid) => uid,
-    Err(_) => match DB.get_user_id_by_username(u.to_lowercase().as_str()) {
-      Some(uid) => uid,
-      None => 0,
-    },
-  });
-
-  let ticket = {
-    let keyword_list = s_kwd_list.iter().map(|(opt, kwd)| {
-      let a = if *opt & 0b01 == 0 { db::Relation::Include(kwd.to_lowercase().clone()) } else { db::Relation::Exclude(kwd.to_lowercase().clone()) };
-      if *opt & 0b10 == 0 { db::SearchMode::PartialMatch(a) } else { db::SearchMode::ExactMatch(a) }
-    }).collect::<Vec<_>>();
-    let tag_list = s_tag_list.iter().map(|(include, tag)| {
-      let i = DB.get_tag_id_by_name(tag.as_str()).unwrap_or(u32::max_value());
-      if *include == 1 { db::Relation::Include(i) } else { db::Relation::Exclude(i) }
-    }).collect::<Vec<_>>();
-    let year_list = s_year_list.iter().map(|(from, to)| match from {
-      Some(a) => {
-        match to {
-          Some(b) => db::SearchRange::Range(*a as u32, *b as u32),
-          None => db::SearchRange::RangeFrom(*a as u32),
-        }
-      },
-      None => {
-        match to {
-          Some(b) => db::SearchRange::RangeTo(*b as u32),
-          None => db::SearchRange::RangeFull,
-        }
-      }
-    }).collect::<Vec<_>>();
-
-    let r18_mode = match s_r18 {
-      0b01 => Some(false),
-      0b10 => Some(true),
-      0b11 => None,
-      _ => return actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad r18_mode"),
-    };
-    let fav_mode = match s_fav {
-      0b01 => Some(false),
-      0b10 => Some(true),
-      0b11 => None,
-      _ => return actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad fav_mode"),
-    };
-    
-    db::SearchTicket {
-      keyword_list,
-      tag_list,
-      year_list,
-      rank: db::SearchRange::RangeFull,
-      rating_count: db::SearchRange::RangeFull,
-      r18: r18_mode,
-      for_user: user_id,
-      fav_mode: fav_mode,
-    }
-  };
+  let (SearchQuery { kwd_list: s_kwd_list, tag_list: s_tag_list, user: s_user, r18: s_r18, fav: s_fav, date: s_date, .. }, ticket, user_error) = match parse_query_str(query_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
+  let user_id = ticket.for_user;
 
   /* perform query */
-  let result = DB.search_by_ticket(&ticket);
-  let result_count = result.len();
-  if n_skip as usize >= result_count {
+  let (result, query_timing) = perform_search(&ticket, &sort_key_list, n_skip as usize + CONFIG.page_size);
+  let result_count = result.result_count;
+  if n_skip as usize >= result_count || user_error.is_some() {
     //return actix_web::HttpResponse::NotFound().content_type("text/plain").body(format!("404 ({} out of {})\n{:#?}", n_skip, result_count, ticket));
     code = 404;
   }
-  let result = db::sort_result(result, sort_mode, is_sort_ascent);
+  let facet = &result.facet;
 
   /* PresentationSearch */
   let percent_query_str = utf8_percent_encode(&query_str, DEFAULT_ENCODE_SET).to_string();
-  let this_year = Date::<FixedOffset>::from_utc(Utc::today().naive_utc(), FixedOffset::east(9 * 3600)).year() as u32;
   let search_obj = PresentationSearch {
     kwd_str: s_kwd_list.iter().map(|(opt, kwd)| format!("{}{}{}", if *opt & 0b10 == 0 { "" } else { "*" }, if *opt & 0b01 == 0 { "" } else { "-" }, kwd)).fold(String::new(), |acc, x| if acc.is_empty() { acc } else { acc + " " } + x.as_str()),
     user: s_user.unwrap_or(String::new()),
-    year: (
-      ticket.year_list.contains(&db::SearchRange::RangeTo(2000)),
-      ticket.year_list.contains(&db::SearchRange::Range(2000, 2005)),
-      ticket.year_list.contains(&db::SearchRange::Range(2005, 2009)),
-      ticket.year_list.contains(&db::SearchRange::Range(2009, 2015)),
-      ticket.year_list.contains(&db::SearchRange::RangeFrom(2015)),
-      ticket.year_list.contains(&db::SearchRange::Range(this_year, this_year + 1)),
-    ),
-    tag: (
-      match s_tag_list.iter().find(|x| x.1 == "奇幻") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "科幻") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "冒险") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "轻小说改") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "漫画改") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "游戏改") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "GAL改") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "日常") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "搞笑") { Some(x) => x.0, None => 2 },
-      match s_tag_list.iter().find(|x| x.1 == "里番") { Some(x) => x.0, None => 2 },
-    ),
+    known_user: user_id.and_then(known_user_to_presentation),
+    year: year_to_presentation(&ticket.year_list, facet),
+    season: season_to_presentation(&ticket.season_list, facet),
+    date: (query_date_to_presentation(s_date.0), query_date_to_presentation(s_date.1)),
+    current_season: current_season_to_presentation(),
+    sub_type: sub_type_to_presentation(&ticket.sub_type_list, facet),
+    tag: tag_panel_to_presentation(&s_tag_list, facet),
+    tag_datalist: tag_datalist_to_presentation(),
     ord: encode_sort_mode_str_to_u8(&sort_mode_str),
+    sort_mode: sort_mode_str.clone(),
     r18: s_r18,
+    r18_count: (facet.r18_count, facet.subject_count - facet.r18_count),
     fav: s_fav,
+    collection: collection_to_presentation(&ticket.collection_list),
     base_url: format!("/search/{}", percent_query_str),
     curr_skip: n_skip,
   };
 
   let mut context = Context::new();
-  let result = result.into_iter().skip(n_skip as usize).take(25).map(|x| subject_to_presentation(&x.subject, user_id.map(|_| total_subject_count - (x.user_recommend as usize)))).collect::<Vec<_>>();
+  let result = result.result_list.iter().skip(n_skip as usize).take(CONFIG.page_size).map(|x| {
+    let (name_span_list, name_cn_span_list) = ticket.highlight_span_list(x.subject);
+    subject_to_presentation(x.subject, user_id, user_id.map(|_| total_subject_count - (x.user_recommend as usize)), &name_span_list, &name_cn_span_list)
+  }).collect::<Vec<_>>();
   context.insert("d_fac", S_D_FAC.as_str());
   context.insert("rev", &REV);
+  context.insert("page_size", &CONFIG.page_size);
   if code == 200 {
-    context.insert("pager", &construct_pager(n_skip / 25, ((result_count + 24) / 25) as u32, move |x| format!("/search/{}/{}/{}", percent_query_str, sort_mode_str, x * 25)));
+    let page_size = CONFIG.page_size as u32;
+    context.insert("pager", &construct_pager(n_skip / page_size, ((result_count + CONFIG.page_size - 1) / CONFIG.page_size) as u32, move |x| format!("/search/{}/{}/{}", percent_query_str, sort_mode_str, x * page_size)));
     context.insert("subject_list", &result);
   }
+  context.insert("unknown_tag_list", &unknown_tag_to_presentation(&s_tag_list));
+  context.insert("user_error", &user_error);
   context.insert("search", &search_obj);
   context.insert("db_date", S_DB_DATE.as_str());
-  context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
+  context.insert("query_time", &query_timing.describe(start_time.elapsed()));
   context.insert("code", &code);
   match code {
     200 => actix_web::HttpResponse::Ok(),
@@ -329,12 +952,385 @@ fn searched(info: actix_web::Path<(String, String, u32,)>) -> impl Responder {
   }.content_type("text/html").body(TERA.render("hako_list_tiny.html", &context).unwrap())
 }
 
+// Search for every subject carrying the tag, ranked for `user_id` when given
+fn tag_search_link(name: &str, user_id: Option<u32>) -> String {
+  let query_str = format!("[[],[[1,{}]],[],{},3,3]", serde_json::to_string(name).unwrap(), user_id.map_or("null".to_string(), |x| format!("\"{}\"", x)));
+  format!("/search/{}/dr/0", utf8_percent_encode(&query_str, PATH_SEGMENT_ENCODE_SET))
+}
+
+// Search ranked for `user_id`, `fav` as in query_str
+fn user_search_link(user_id: u32, fav: u8) -> String {
+  let query_str = format!("[[],[],[],\"{}\",3,{}]", user_id, fav);
+  format!("/search/{}/dr/0", utf8_percent_encode(&query_str, PATH_SEGMENT_ENCODE_SET))
+}
+
+/* subject */
+fn subject(info: actix_web::Path<(u32,)>, query: actix_web::Query<SubjectQuery>) -> impl Responder {
+  let start_time = Instant::now();
+  let (subject_id,) = info.into_inner();
+  let x = match DB.get_subject_by_id(subject_id) {
+    Some(x) => x,
+    None => return actix_web::HttpResponse::NotFound().content_type("text/plain").body(format!("404 (subject {} not found)", subject_id)),
+  };
+
+  /* user */
+  let total_subject_count = DB.subject_count();
+  let user = query.user.as_ref().map_or("", |x| x.trim()).to_string();
+  let user_lookup = if user.is_empty() { None } else { Some(lookup_user(&user)) };
+  let user_error = user_lookup.as_ref().and_then(|x| x.to_error());
+  let user_id = match user_lookup {
+    Some(UserLookup::Found(uid)) => Some(uid),
+    _ => None,
+  };
+  let user_recommend = |subject_id: u32| user_id.and_then(|uid| DB.get_user_subject_relation(uid, subject_id)).map(|r| total_subject_count - r as usize);
+  let recommend_rate = user_recommend(subject_id);
+
+  /* PresentationSubjectDetail */
+  let mut tag_list = x.tag_list.clone();
+  tag_list.sort_by(|a, b| b.1.total_cmp(&a.1));
+  let subject_obj = PresentationSubjectDetail {
+    subject_id,
+    external_link: CONFIG.subject_url(subject_id),
+    image_url: cover_url(x),
+    title_main: if !x.name_cn.is_empty() { x.name_cn.clone() } else { x.name.clone() },
+    title_orig: if x.name_cn.is_empty() { None } else { Some(x.name.clone()) },
+    sub_type: encode_sub_type_to_str(x.sub_type),
+    info: format!("{:02}/{:02}/{:04}", x.air_m, x.air_d, x.air_y),
+    rank: x.rank,
+    star_count: format!("{:.2}", x.score),
+    rating_count: x.rating_count,
+    is_r18: x.is_r18,
+    tag_list: tag_list.into_iter().filter_map(|(tag_id, weight)| DB.get_tag_name_by_id(tag_id).map(|name| PresentationSubjectTag {
+      name: name.to_string(),
+      weight: format!("{}", weight),
+      search_link: tag_search_link(name, user_id),
+    })).collect(),
+    similar_list: DB.similar_subjects(x, SIMILAR_SUBJECT_COUNT).into_iter().map(|(y, similarity)| {
+      (subject_to_presentation(y, user_id, user_id.and(user_recommend(y.subject_id)), &[], &[]), format!("{:.0}%", similarity * 100.0))
+    }).collect(),
+    user,
+    known_user: user_id.and_then(known_user_to_presentation),
+    recommend_rate: recommend_rate.map(|u| format!("{}", u)),
+    is_fav: user_id.map_or(false, |uid| DB.is_user_fav(uid, subject_id)),
+  };
+
+  let mut context = Context::new();
+  context.insert("subject", &subject_obj);
+  context.insert("user_error", &user_error);
+  context.insert("total_subject_count", &total_subject_count);
+  context.insert("d_fac", S_D_FAC.as_str());
+  context.insert("rev", &REV);
+  context.insert("db_date", S_DB_DATE.as_str());
+  context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
+  actix_web::HttpResponse::Ok().content_type("text/html").body(TERA.render("hako_subject_tiny.html", &context).unwrap())
+}
+
+// UID of a user in the model, or a 404 telling why there is none, in JSON when `json`
+fn resolve_user(name_or_id: &str, json: bool) -> Result<u32, actix_web::HttpResponse> {
+  let user_lookup = lookup_user(name_or_id);
+  let error = match user_lookup.to_error() {
+    Some(x) => x,
+    None => { return Ok(user_lookup.user_id()); }
+  };
+  if json {
+    return Err(actix_web::HttpResponse::NotFound().json(error));
+  }
+  let user = error.user.clone().unwrap_or_default();
+  Err(actix_web::HttpResponse::NotFound().content_type("text/plain").body(match error.kind {
+    "excluded_user" => format!("404 (user {} has too few favourites to be in the model)", user),
+    _ if error.suggestion_list.is_empty() => format!("404 (user {} not found)", user),
+    _ => format!("404 (user {} not found, did you mean {})", user, error.suggestion_list.join(", ")),
+  }))
+}
+
+/* user */
+fn user(info: actix_web::Path<(String,)>) -> impl Responder {
+  let start_time = Instant::now();
+  let (name_or_id,) = info.into_inner();
+  let user_id = match resolve_user(&name_or_id, false) { Ok(x) => x, Err(r) => { return r; } };
+  let fav_list = DB.get_user_fav_list(user_id).unwrap();
+
+  let total_subject_count = DB.subject_count();
+  let fav_subject_list = fav_list.iter().filter_map(|x| DB.get_subject_by_id(*x)).collect::<Vec<_>>();
+  let fav_count = fav_subject_list.len();
+  let fav_subject_list = db::top_k_result_unsearched(fav_subject_list, &[db::SortKey { mode: db::SortMode::Rank, ascent: false }], USER_FAV_SIZE);
+
+  /* PresentationUser */
+  let user_obj = PresentationUser {
+    user_id,
+    name: DB.get_user_username_by_id(user_id).map_or(name_or_id, |x| x.to_string()),
+    fav_count,
+    tag_profile: DB.user_tag_profile(user_id).into_iter().take(USER_TAG_PROFILE_SIZE).map(|(tag_id, weight)| {
+      let name = DB.get_tag_name_by_id(tag_id).unwrap();
+      PresentationSubjectTag {
+        name: name.to_string(),
+        weight: format!("{:.0}%", weight * 100.0 / fav_count.max(1) as f32),
+        search_link: tag_search_link(name, Some(user_id)),
+      }
+    }).collect(),
+    recommend_list: DB.recommend_for_user(user_id, USER_RECOMMEND_SIZE).into_iter().map(|(x, relation)| {
+      subject_to_presentation(x, Some(user_id), Some(total_subject_count - relation as usize), &[], &[])
+    }).collect(),
+    fav_list: fav_subject_list.into_iter().map(|x| {
+      subject_to_presentation(x, Some(user_id), DB.get_user_subject_relation(user_id, x.subject_id).map(|r| total_subject_count - r as usize), &[], &[])
+    }).collect(),
+    recommend_link: user_search_link(user_id, 1),
+    fav_link: user_search_link(user_id, 2),
+    similar_link: format!("/similar/{}", user_id),
+  };
+
+  let mut context = Context::new();
+  context.insert("user", &user_obj);
+  context.insert("d_fac", S_D_FAC.as_str());
+  context.insert("rev", &REV);
+  context.insert("db_date", S_DB_DATE.as_str());
+  context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
+  actix_web::HttpResponse::Ok().content_type("text/html").body(TERA.render("hako_user_tiny.html", &context).unwrap())
+}
+
+fn comparison_to_presentation(user_a: u32, user_b: u32) -> PresentationComparison {
+  let comparison = DB.compare_users(user_a, user_b, COMPARE_LIST_SIZE).unwrap();
+  let total_subject_count = DB.subject_count();
+  let cross_to_presentation = |l: Vec<(&db::PackedSubject, u16)>, user_id: u32| l.into_iter().map(|(x, relation)| {
+    subject_to_presentation(x, Some(user_id), Some(total_subject_count - relation as usize), &[], &[])
+  }).collect::<Vec<_>>();
+  PresentationComparison {
+    user_a: known_user_to_presentation(user_a).unwrap(),
+    user_b: known_user_to_presentation(user_b).unwrap(),
+    fav_count: (DB.get_user_fav_list(user_a).unwrap().len(), DB.get_user_fav_list(user_b).unwrap().len()),
+    fav_jaccard: comparison.fav_jaccard,
+    relation_correlation: comparison.relation_correlation,
+    shared_fav_count: comparison.shared_fav_count,
+    shared_fav_list: comparison.shared_fav_list.into_iter().map(|x| {
+      subject_to_presentation(x, Some(user_a), DB.get_user_subject_relation(user_a, x.subject_id).map(|r| total_subject_count - r as usize), &[], &[])
+    }).collect(),
+    a_from_b_list: cross_to_presentation(comparison.a_from_b_list, user_a),
+    b_from_a_list: cross_to_presentation(comparison.b_from_a_list, user_b),
+  }
+}
+
+/* compare */
+fn compare(info: actix_web::Path<(String, String,)>) -> impl Responder {
+  let start_time = Instant::now();
+  let (user_a, user_b,) = info.into_inner();
+  let user_a = match resolve_user(&user_a, false) { Ok(x) => x, Err(r) => { return r; } };
+  let user_b = match resolve_user(&user_b, false) { Ok(x) => x, Err(r) => { return r; } };
+  let compare_obj = comparison_to_presentation(user_a, user_b);
+
+  let mut context = Context::new();
+  context.insert("fav_jaccard", &format!("{:.1}%", compare_obj.fav_jaccard * 100.0));
+  context.insert("relation_correlation", &format!("{:.3}", compare_obj.relation_correlation));
+  context.insert("compare", &compare_obj);
+  context.insert("d_fac", S_D_FAC.as_str());
+  context.insert("rev", &REV);
+  context.insert("db_date", S_DB_DATE.as_str());
+  context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
+  actix_web::HttpResponse::Ok().content_type("text/html").body(TERA.render("hako_compare_tiny.html", &context).unwrap())
+}
+
+fn similar_user_to_presentation(user_id: u32) -> PresentationSimilarUser {
+  let total_subject_count = DB.subject_count();
+  PresentationSimilarUser {
+    user: known_user_to_presentation(user_id).unwrap(),
+    neighbour_list: DB.similar_users(user_id, SIMILAR_USER_SIZE).into_iter().map(|(neighbour_id, score)| {
+      let comparison = DB.compare_users(user_id, neighbour_id, SIMILAR_USER_FAV_SIZE).unwrap();
+      PresentationNeighbour {
+        user: known_user_to_presentation(neighbour_id).unwrap(),
+        score,
+        fav_count: DB.get_user_fav_list(neighbour_id).unwrap().len(),
+        fav_jaccard: comparison.fav_jaccard,
+        relation_correlation: comparison.relation_correlation,
+        shared_fav_count: comparison.shared_fav_count,
+        fav_list: comparison.a_from_b_list.into_iter().map(|(x, relation)| {
+          subject_to_presentation(x, Some(user_id), Some(total_subject_count - relation as usize), &[], &[])
+        }).collect(),
+        compare_link: format!("/compare/{}/{}", user_id, neighbour_id),
+      }
+    }).collect(),
+  }
+}
+
+/* similar */
+fn similar(info: actix_web::Path<(String,)>) -> impl Responder {
+  let start_time = Instant::now();
+  let (name_or_id,) = info.into_inner();
+  let user_id = match resolve_user(&name_or_id, false) { Ok(x) => x, Err(r) => { return r; } };
+
+  let mut context = Context::new();
+  context.insert("similar", &similar_user_to_presentation(user_id));
+  context.insert("d_fac", S_D_FAC.as_str());
+  context.insert("rev", &REV);
+  context.insert("db_date", S_DB_DATE.as_str());
+  context.insert("query_time", &format!("{}μs", start_time.elapsed().as_micros()));
+  actix_web::HttpResponse::Ok().content_type("text/html").body(TERA.render("hako_similar_tiny.html", &context).unwrap())
+}
+
+/* api */
+fn api_suggest(query: actix_web::Query<SuggestQuery>) -> impl Responder {
+  let n = query.n.unwrap_or(SUBJECT_SUGGEST_SIZE).min(50);
+  actix_web::HttpResponse::Ok().json(DB.suggest_subject(query.q.as_str(), n).into_iter().map(|x| PresentationSubjectSuggestion {
+    subject_id: x.subject_id,
+    title_main: if !x.name_cn.is_empty() { x.name_cn.clone() } else { x.name.clone() },
+    title_orig: if x.name_cn.is_empty() { None } else { Some(x.name.clone()) },
+    image_url: cover_url(x),
+  }).collect::<Vec<_>>())
+}
+
+fn api_search(info: actix_web::Path<(String, String, u32,)>) -> impl Responder {
+  let (query_str, sort_mode_str, n_skip,) = info.into_inner();
+  let sort_key_list = match parse_sort_mode_str(sort_mode_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
+  let (query, ticket, user_error) = match parse_query_str(query_str.as_str()) { Ok(x) => x, Err(r) => { return r; } };
+
+  let total_subject_count = DB.subject_count();
+  let (result, _) = perform_search(&ticket, &sort_key_list, n_skip as usize + CONFIG.page_size);
+  let result_count = result.result_count;
+  let error = user_error.or_else(|| if result_count == 0 { Some(PresentationSearchError { kind: "no_match", user: None, suggestion_list: Vec::new() }) } else { None });
+  if error.is_some() { actix_web::HttpResponse::NotFound() } else { actix_web::HttpResponse::Ok() }.json(PresentationSearchResult {
+    result_count,
+    error,
+    username: ticket.for_user.and_then(|x| DB.get_user_username_by_id(x)).map(|x| x.to_string()),
+    subject_list: result.result_list.iter().skip(n_skip as usize).take(CONFIG.page_size).map(|x| {
+      let (name_span_list, name_cn_span_list) = ticket.highlight_span_list(x.subject);
+      subject_to_presentation(x.subject, ticket.for_user, ticket.for_user.map(|_| total_subject_count - (x.user_recommend as usize)), &name_span_list, &name_cn_span_list)
+    }).collect(),
+    facet: facet_to_presentation(&result.facet),
+    unknown_tag_list: unknown_tag_to_presentation(&query.tag_list),
+  })
+}
+
+fn api_compare(info: actix_web::Path<(String, String,)>) -> impl Responder {
+  let (user_a, user_b,) = info.into_inner();
+  let user_a = match resolve_user(&user_a, true) { Ok(x) => x, Err(r) => { return r; } };
+  let user_b = match resolve_user(&user_b, true) { Ok(x) => x, Err(r) => { return r; } };
+  actix_web::HttpResponse::Ok().json(comparison_to_presentation(user_a, user_b))
+}
+
+fn api_similar(info: actix_web::Path<(String,)>) -> impl Responder {
+  let (name_or_id,) = info.into_inner();
+  let user_id = match resolve_user(&name_or_id, true) { Ok(x) => x, Err(r) => { return r; } };
+  actix_web::HttpResponse::Ok().json(similar_user_to_presentation(user_id))
+}
+
+fn api_tag(query: actix_web::Query<SuggestQuery>) -> impl Responder {
+  let n = query.n.unwrap_or(TAG_SUGGEST_SIZE).min(50);
+  actix_web::HttpResponse::Ok().json(tag_suggestion_to_presentation(query.q.as_str(), n))
+}
+
+fn api_stats(_req: actix_web::HttpRequest) -> impl Responder {
+  actix_web::HttpResponse::Ok().json(PresentationStat {
+    query_cache: QUERY_CACHE.stat(),
+  })
+}
+
+// Cached covers carry whatever extension upstream gave them, so trust the magic bytes instead
+fn sniff_image_type(path: &Path) -> Option<mime::Mime> {
+  let mut head = [0u8; 12];
+  let n = std::fs::File::open(path).and_then(|mut f| f.read(&mut head)).ok()?;
+  let head = &head[..n];
+  if head.starts_with(&[0xff, 0xd8, 0xff]) {
+    Some(mime::IMAGE_JPEG)
+  }
+  else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+    Some(mime::IMAGE_PNG)
+  }
+  else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+    Some(mime::IMAGE_GIF)
+  }
+  else if head.len() == 12 && head.starts_with(b"RIFF") && &head[8..] == b"WEBP" {
+    "image/webp".parse().ok()
+  }
+  else {
+    None
+  }
+}
+
+// `<size>/<path>` inside `cache_dir`, None for anything that could leave it
+fn cover_cache_path(cache_dir: &str, size: &str, partial_path: &str) -> Option<PathBuf> {
+  if !config::COVER_SIZE_LIST.contains(&size) || partial_path.contains('\\') {
+    return None;
+  }
+  if partial_path.split('/').any(|x| x.is_empty() || x == "." || x == "..") {
+    return None;
+  }
+  Some(Path::new(cache_dir).join(size).join(partial_path))
+}
+
+fn cover(req: actix_web::HttpRequest, info: actix_web::Path<(String, String,)>) -> actix_web::HttpResponse {
+  let (size, partial_path,) = info.into_inner();
+  if CONFIG.cover_cache_dir.is_empty() {
+    return actix_web::HttpResponse::NotFound().content_type("text/plain").body("Cover cache disabled");
+  }
+  let path = match cover_cache_path(&CONFIG.cover_cache_dir, &size, &partial_path) {
+    Some(x) => x,
+    None => return actix_web::HttpResponse::BadRequest().content_type("text/plain").body("Bad cover path"),
+  };
+  /* cached cover */
+  if let Some(mime_type) = sniff_image_type(&path) {
+    if let Ok(mut resp) = actix_web::fs::NamedFile::open(&path).and_then(|f| f.set_content_type(mime_type).respond_to(&req)) {
+      resp.headers_mut().insert(http::header::CACHE_CONTROL, http::header::HeaderValue::from_str(&format!("public, max-age={}", COVER_MAX_AGE)).unwrap());
+      return resp;
+    }
+  }
+  /* placeholder, not cached so that a later fetch shows up */
+  match actix_web::fs::NamedFile::open(Path::new(&CONFIG.static_dir).join(COVER_PLACEHOLDER)).and_then(|f| f.respond_to(&req)) {
+    Ok(mut resp) => {
+      resp.headers_mut().insert(http::header::CACHE_CONTROL, http::header::HeaderValue::from_static("no-cache"));
+      resp
+    },
+    Err(_) => actix_web::HttpResponse::NotFound().content_type("text/plain").body("Cover not found"),
+  }
+}
+
+// `--list-covers`, one `<size>/<path>` per line for an offline fetch into cover_cache_dir
+fn list_covers(size_list: &[String]) {
+  for subject in DB.subject_iter().filter(|x| !x.image_partial_url.is_empty()) {
+    let (_, path) = config::split_cover_partial(&subject.image_partial_url);
+    for size in size_list.iter() {
+      println!("{}/{}", size, path);
+    }
+  }
+}
+
 fn main() {
+  lazy_static::initialize(&CONFIG);
+  if let Some(size_list) = &CONFIG.list_covers {
+    list_covers(size_list);
+    return;
+  }
   &*DB;
-  server::new(|| {
+  let s = server::new(|| {
     App::new()
-    .handler("/static", actix_web::fs::StaticFiles::new("./static").unwrap().show_files_listing())
+    .handler("/static", actix_web::fs::StaticFiles::new(CONFIG.static_dir.as_str()).unwrap().show_files_listing())
+    .route("/img/{size}/{partial_path:.*}", http::Method::GET, cover)
+    .route("/api/stats", http::Method::GET, api_stats)
+    .route("/api/tag", http::Method::GET, api_tag)
+    .route("/api/suggest", http::Method::GET, api_suggest)
+    .route("/api/search/{query_str}/{sort_mode}/{n_skip}", http::Method::GET, api_search)
+    .route("/api/compare/{user_a}/{user_b}", http::Method::GET, api_compare)
+    .route("/api/similar/{name_or_id}", http::Method::GET, api_similar)
+    .route("/subject/{subject_id}", http::Method::GET, subject)
+    .route("/user/{name_or_id}", http::Method::GET, user)
+    .route("/compare/{user_a}/{user_b}", http::Method::GET, compare)
+    .route("/similar/{name_or_id}", http::Method::GET, similar)
     .route("/{sort_mode}/{n_skip}", http::Method::GET, unsearched)
     .route("/search/{query_str}/{sort_mode}/{n_skip}", http::Method::GET, searched)
-  }).bind("127.0.0.1:8080").unwrap().run();
-}
\ No newline at end of file
+  });
+  let s = if CONFIG.workers > 0 { s.workers(CONFIG.workers) } else { s };
+  s.bind(CONFIG.bind_addr()).unwrap().run();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cover_cache_path_stays_inside_cache_dir() {
+    assert_eq!(cover_cache_path("covers", "c", "ab/cd/1_xyz.jpg"), Some(Path::new("covers/c/ab/cd/1_xyz.jpg").to_path_buf()));
+    for partial_path in ["..", "../secret", "ab/../../secret", "ab/..", ".", "./ab.jpg", "ab/./cd.jpg", "", "ab//cd.jpg", "ab/", "/etc/passwd", "ab\\..\\..\\secret", "..\\secret"].iter() {
+      assert_eq!(cover_cache_path("covers", "c", partial_path), None, "{}", partial_path);
+    }
+    for size in ["", "x", "..", "c/..", "C"].iter() {
+      assert_eq!(cover_cache_path("covers", size, "ab/cd/1_xyz.jpg"), None, "{}", size);
+    }
+  }
+}
@@ -4,13 +4,18 @@
   let submit_button = document.getElementById("SubmitWorkSearchBox");
   let year_box = document.getElementById("year_box");
   let tag_box = document.getElementById("tag_box");
+  let sub_type_box = document.getElementById("sub_type_box");
+  let season_box = document.getElementById("season_box");
+  let date_from = document.getElementById("DateFrom");
+  let date_to = document.getElementById("DateTo");
+  let current_season = document.getElementById("CurrentSeason");
   let r18_box = document.getElementById("r18_box");
   let fav_box = document.getElementById("fav_box");
+  let collection_box = document.getElementById("collection_box");
   let sort_mode = document.getElementById("WorkSortMode");
   let pager_submit = document.getElementById("PagerSubmit");
   let search_panel = document.getElementById("WorkSearchPanel");
   let pager_value = document.getElementById("PagerValue");
-  const ord_map_list = ["ar", "dr", "al", "dl", "ak", "dk", "ad", "dd", "af", "df"];
 
   function bind_year(e) {
     let l = e.getElementsByClassName("WorkFilterItem");
@@ -24,20 +29,68 @@
     }
   }
 
+  function toggle_tag(ev) {
+    if(this.classList.contains("Positive")) {
+      this.classList.remove("Positive");
+      this.classList.add("Negative");
+    }
+    else if(this.classList.contains("Negative"))
+      this.classList.remove("Negative");
+    else
+      this.classList.add("Positive");
+  }
+
   function bind_tag(e) {
     let l = e.getElementsByClassName("WorkFilterItem");
-    for(let i = 0; i < l.length; ++i) {
-      l[i].addEventListener("click", function(ev){
-        if(this.classList.contains("Positive")) {
-          this.classList.remove("Positive");
-          this.classList.add("Negative");
+    for(let i = 0; i < l.length; ++i)
+      l[i].addEventListener("click", toggle_tag);
+  }
+
+  function bind_tag_add(e) {
+    let datalist = document.getElementById("TagDatalist");
+    let timer = null;
+    e.addEventListener("input", function(ev){
+      let q = this.value.trim();
+      if(timer)
+        clearTimeout(timer);
+      if(!q)
+        return;
+      timer = setTimeout(function(){
+        fetch("/api/tag?q=" + encodeURIComponent(q)).then(r => r.json()).then(function(l){
+          datalist.innerHTML = "";
+          for(let i = 0; i < l.length; ++i) {
+            let x = document.createElement("option");
+            x.value = l[i].name;
+            x.label = l[i].name + " (" + l[i].count + ")";
+            datalist.appendChild(x);
+          }
+        });
+      }, 200);
+    });
+    e.addEventListener("keydown", function(ev){
+      if(ev.key != "Enter")
+        return;
+      ev.stopPropagation();
+      ev.preventDefault();
+      let name = this.value.trim();
+      if(!name)
+        return;
+      let l = tag_box.getElementsByClassName("WorkFilterItem");
+      for(let i = 0; i < l.length; ++i) {
+        if(l[i].innerText.toLowerCase() == name.toLowerCase()) {
+          l[i].classList.remove("Negative");
+          l[i].classList.add("Positive");
+          this.value = "";
+          return;
         }
-        else if(this.classList.contains("Negative"))
-          this.classList.remove("Negative");
-        else
-          this.classList.add("Positive");
-      });
-    }
+      }
+      let x = document.createElement("div");
+      x.className = "WorkFilterItem Positive";
+      x.innerText = name;
+      x.addEventListener("click", toggle_tag);
+      tag_box.insertBefore(x, this);
+      this.value = "";
+    });
   }
 
   function bind_single3(e) {
@@ -61,8 +114,62 @@
     }
   }
 
+  function bind_suggest(e) {
+    let datalist = document.getElementById("WorkSearchDatalist");
+    let timer = null;
+    e.addEventListener("input", function(ev){
+      let q = this.value.trim();
+      if(timer)
+        clearTimeout(timer);
+      // only the plain single keyword case is worth suggesting
+      if(!q || q.indexOf(" ") != -1 || q[0] == "-" || q[0] == "*")
+        return;
+      timer = setTimeout(function(){
+        fetch("/api/suggest?q=" + encodeURIComponent(q)).then(r => r.json()).then(function(l){
+          datalist.innerHTML = "";
+          for(let i = 0; i < l.length; ++i) {
+            let x = document.createElement("option");
+            x.value = l[i].title_main;
+            if(l[i].title_orig)
+              x.label = l[i].title_orig;
+            datalist.appendChild(x);
+          }
+        });
+      }, 150);
+    });
+  }
+
+  function bind_current_season(e) {
+    e.addEventListener("click", function(ev){
+      if(this.classList.contains("Positive")) {
+        this.classList.remove("Positive");
+        date_from.value = "";
+        date_to.value = "";
+      }
+      else {
+        this.classList.add("Positive");
+        date_from.value = this.dataset.from;
+        date_to.value = this.dataset.to;
+      }
+    });
+    let f = function(){
+      if(date_from.value == e.dataset.from && date_to.value == e.dataset.to)
+        e.classList.add("Positive");
+      else
+        e.classList.remove("Positive");
+    };
+    date_from.addEventListener("change", f);
+    date_to.addEventListener("change", f);
+  }
+
+  bind_suggest(search_box);
   bind_year(year_box);
+  bind_year(season_box);
+  bind_year(sub_type_box);
+  bind_current_season(current_season);
   bind_tag(tag_box);
+  bind_tag(collection_box);
+  bind_tag_add(document.getElementById("TagAddBox"));
   bind_single3(r18_box);
   bind_single3(fav_box);
 
@@ -72,7 +179,7 @@
       let min = parseInt(pager_value.min);
       let max = parseInt(pager_value.max);
       if(v >= min && v <= max) {
-        window.location = search_panel.dataset.origBase + "/" + ord_map_list[parseInt(sort_mode.dataset.ord)] + "/" + (v - 1) * 25;
+        window.location = search_panel.dataset.origBase + "/" + sort_mode.dataset.sort + "/" + (v - 1) * parseInt(search_panel.dataset.pageSize);
         ev.stopPropagation();
         ev.preventDefault();
       }
@@ -102,48 +209,54 @@
       let l = tag_box.getElementsByClassName("WorkFilterItem");
       for(let i = 0; i < l.length; ++i) {
         let x = l[i];
+        let t;
         if(x.classList.contains("Positive"))
-          tag_list.push([1, x.innerText]);
+          t = [1, x.innerText];
         else if(x.classList.contains("Negative"))
-          tag_list.push([0, x.innerText]);
+          t = [0, x.innerText];
+        else
+          continue;
+        if(x.dataset.minWeight || x.dataset.topN)
+          t.push(x.dataset.minWeight ? parseFloat(x.dataset.minWeight) : null);
+        if(x.dataset.topN)
+          t.push(parseInt(x.dataset.topN));
+        tag_list.push(t);
       }
     }
     
-    function translate_year(s) {
-      if(s == "Now") {
-        let y = 1900 + new Date().getYear();
-        return [y, y + 1]
-      }
-      let l;
-      if(l = s.match(/^\((\d{4})\.\.(\d{4})\)$/)) {
-        let a = parseInt(l[1]);
-        let b = parseInt(l[2]);
-        return [a, b];
-      }
-      else if(l = s.match(/^\((\d{4})\.\.\)$/)) {
-        let a = parseInt(l[1]);
-        return [a, null];
-      }
-      else if(l = s.match(/^\(\.\.(\d{4})\)$/)) {
-        let b = parseInt(l[1]);
-        return [null, b];
+    let year_list = [];
+    {
+      let l = year_box.getElementsByClassName("WorkFilterItem");
+      for(let i = 0; i < l.length; ++i) {
+        let x = l[i];
+        if(x.classList.contains("Positive"))
+          year_list.push([x.dataset.from ? parseInt(x.dataset.from) : null, x.dataset.to ? parseInt(x.dataset.to) : null]);
       }
-      else {
-        let a = parseInt(s);
-        return [a, a + 1];
+    }
+    
+    let sub_type_list = [];
+    {
+      let l = sub_type_box.getElementsByClassName("WorkFilterItem");
+      for(let i = 0; i < l.length; ++i) {
+        let x = l[i];
+        if(x.classList.contains("Positive"))
+          sub_type_list.push(parseInt(x.dataset.subType));
       }
     }
 
-    let year_list = [];
+    let season_list = [];
     {
-      let l = year_box.getElementsByClassName("WorkFilterItem");
+      let l = season_box.getElementsByClassName("WorkFilterItem");
       for(let i = 0; i < l.length; ++i) {
         let x = l[i];
         if(x.classList.contains("Positive"))
-          year_list.push(translate_year(x.innerText));
+          season_list.push(parseInt(x.dataset.season));
       }
     }
-    
+
+    // yyyymmdd, both ends inclusive
+    let date_range = [date_from.value, date_to.value].map(x => x ? parseInt(x.replace(/-/g, "")) : null);
+
     let u = user_box.value;
     if(!u)
       u = null;
@@ -168,8 +281,27 @@
         exclude_fav_mode = 3;
     }
     
-    let s = JSON.stringify([kwd_list, tag_list, year_list, u, r18_mode, exclude_fav_mode]);
-    if(s.length > 127){
+    let collection_list = [];
+    {
+      let l = collection_box.getElementsByClassName("WorkFilterItem");
+      for(let i = 0; i < l.length; ++i) {
+        let x = l[i];
+        if(x.classList.contains("Positive"))
+          collection_list.push([1, parseInt(x.dataset.collectionState)]);
+        else if(x.classList.contains("Negative"))
+          collection_list.push([0, parseInt(x.dataset.collectionState)]);
+      }
+    }
+
+    let q = [kwd_list, tag_list, year_list, u, r18_mode, exclude_fav_mode];
+    // optional trailing items are left out while they hold their default value
+    let extra = [sub_type_list, season_list, date_range, collection_list];
+    while(extra.length > 0 && extra[extra.length - 1].every(x => x == null))
+      extra.pop();
+    q = q.concat(extra);
+    let s = JSON.stringify(q);
+    // same limit as QUERY_STR_MAX_LEN on the server, counted in UTF-8 bytes
+    if(new TextEncoder().encode(s).length > 1024){
       alert("查询字符串过长，请考虑缩短关键词长度");
       return;
     }